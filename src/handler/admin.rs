@@ -0,0 +1,60 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+
+use super::client_info::ClientInfo;
+use crate::sys::app_set::AppSet;
+
+// 管理用エンドポイントのパス
+pub const SCOPE_PATH: &str = "/_admin";
+
+/// 管理用エンドポイント (admin_networksからのアクセスのみ)
+pub fn scope() -> actix_web::Scope {
+    web::scope(SCOPE_PATH)
+        .service(not_found_json)
+        .service(not_found_csv)
+        .service(clear_render_cache)
+        .service(block_rules_json)
+        // どのルートがあるか分からないように、ないものは405ではなく404にする
+        .default_service(web::to(|| async { HttpResponse::NotFound().finish() }))
+}
+
+fn allowed(app_set: &AppSet, req: &HttpRequest) -> bool {
+    let ip = ClientInfo::get(req).ip;
+    app_set.app_config.admin_networks.iter().any(|net| net.contains(&ip))
+}
+
+#[actix_web::get("/404.json")]
+async fn not_found_json(app_set: web::Data<AppSet>, req: HttpRequest) -> impl Responder {
+    if !allowed(&app_set, &req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok().json(app_set.not_found_log.snapshot())
+}
+
+#[actix_web::get("/404.csv")]
+async fn not_found_csv(app_set: web::Data<AppSet>, req: HttpRequest) -> impl Responder {
+    if !allowed(&app_set, &req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(("Content-Disposition", "attachment; filename=\"404.csv\""))
+        .body(app_set.not_found_log.to_csv())
+}
+
+#[actix_web::post("/render-cache/clear")]
+async fn clear_render_cache(app_set: web::Data<AppSet>, req: HttpRequest) -> impl Responder {
+    if !allowed(&app_set, &req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    app_set.clear_render_cache();
+    HttpResponse::NoContent().finish()
+}
+
+#[actix_web::get("/block-rules.json")]
+async fn block_rules_json(app_set: web::Data<AppSet>, req: HttpRequest) -> impl Responder {
+    if !allowed(&app_set, &req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let hits = app_set.block_rules.as_ref().map(|b| b.snapshot()).unwrap_or_default();
+    HttpResponse::Ok().json(hits)
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use super::basic_auth::BasicAuth;
+use super::path::encode_path;
+use super::security_headers::csp_nonce;
+use crate::sys::init::AutoIndexConfig;
+
+struct Rule {
+    path: String,
+    show_hidden: bool,
+    exclude: Vec<Pattern>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    href: String,
+    is_dir: bool,
+    size: u64,
+    mtime: Option<String>,
+    #[serde(skip)]
+    modified: Option<SystemTime>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    sort: Option<String>,
+    order: Option<String>,
+    format: Option<String>,
+}
+
+impl Rule {
+    /// 一覧に出さない名前か
+    fn hides(&self, name: &str) -> bool {
+        (!self.show_hidden && name.starts_with('.')) || self.exclude.iter().any(|p| p.matches(name))
+    }
+}
+
+/// index.htmlのないディレクトリの一覧表示 (設定したディレクトリ以下のみ)
+pub struct AutoIndex {
+    rules: Vec<Rule>,
+}
+
+impl AutoIndex {
+    pub fn new(configs: &[AutoIndexConfig]) -> Self {
+        let rules = configs.iter()
+            .map(|config| Rule {
+                // ディレクトリの境界で一致させるため末尾を "/" にそろえる ("builds" が "builds-secret/" に一致しないように)
+                path: match config.path.trim_matches('/') {
+                    "" => String::new(),
+                    path => format!("{}/", path),
+                },
+                show_hidden: config.show_hidden,
+                exclude: config.exclude.iter()
+                    .map(|p| Pattern::new(p).expect("Invalid autoindex exclude pattern"))
+                    .collect(),
+            })
+            .collect();
+        AutoIndex { rules }
+    }
+
+    /// ディレクトリ (末尾 "/" 付き、ルートは "") で一覧表示が有効か
+    pub fn enabled(&self, dir: &str) -> bool {
+        self.rule_for(dir).is_some()
+    }
+
+    fn rule_for(&self, dir: &str) -> Option<&Rule> {
+        // 最も長く一致した設定を使う
+        let rule = self.rules.iter()
+            .filter(|rule| dir.starts_with(&rule.path))
+            .max_by_key(|rule| rule.path.len())?;
+        // 一覧に出さないディレクトリ ("builds/.git/" など) の中も表示しない
+        let hidden = dir[rule.path.len()..].split('/').any(|name| !name.is_empty() && rule.hides(name));
+        (!hidden).then_some(rule)
+    }
+
+    pub fn render(
+        &self,
+        req: &HttpRequest,
+        dir: &str,
+        static_cache: &HashMap<String, Bytes>,
+        modified: &HashMap<String, SystemTime>,
+        template: &Tera,
+        basic_auth: &BasicAuth,
+    ) -> HttpResponse {
+        let Some(rule) = self.rule_for(dir) else {
+            return HttpResponse::NotFound().finish();
+        };
+        let query = web::Query::<ListQuery>::from_query(req.query_string())
+            .map(|q| q.into_inner())
+            .unwrap_or(ListQuery { sort: None, order: None, format: None });
+
+        // キャッシュのキーからディレクトリ直下のエントリを集める
+        let mut entries: BTreeMap<String, Entry> = BTreeMap::new();
+        for (key, content) in static_cache {
+            let Some(rest) = key.strip_prefix(dir) else { continue };
+            // 別の認証で保護されたファイルは名前も見せない
+            if basic_auth.hides(dir, key) {
+                continue;
+            }
+            let (name, is_dir) = match rest.split_once('/') {
+                Some((name, _)) => (name, true),
+                None => (rest, false),
+            };
+            if name.is_empty() || rule.hides(name) {
+                continue;
+            }
+
+            let entry = entries.entry(name.to_string()).or_insert_with(|| Entry {
+                name: name.to_string(),
+                href: encode_path(&format!("{}{}{}", dir, name, if is_dir { "/" } else { "" })),
+                is_dir,
+                size: 0,
+                mtime: None,
+                modified: None,
+            });
+            // ディレクトリは中身の合計サイズと最新の更新日時
+            entry.size += content.len() as u64;
+            if let Some(time) = modified.get(key) {
+                if entry.modified.is_none_or(|m| m < *time) {
+                    entry.modified = Some(*time);
+                    entry.mtime = Some(DateTime::<Utc>::from(*time).to_rfc3339());
+                }
+            }
+        }
+        if entries.is_empty() && !dir.is_empty() {
+            return HttpResponse::NotFound().finish();
+        }
+
+        let mut entries: Vec<Entry> = entries.into_values().collect();
+        let sort = query.sort.as_deref().unwrap_or("name");
+        match sort {
+            "size" => entries.sort_by_key(|e| e.size),
+            "mtime" => entries.sort_by_key(|e| e.modified),
+            _ => {}
+        }
+        let desc = query.order.as_deref() == Some("desc");
+        if desc {
+            entries.reverse();
+        }
+        // ディレクトリを先に表示する
+        entries.sort_by_key(|e| !e.is_dir);
+
+        if query.format.as_deref() == Some("json") {
+            return HttpResponse::Ok().json(serde_json::json!({
+                "path": format!("/{}", dir),
+                "entries": entries,
+            }));
+        }
+
+        let mut context = Context::new();
+        context.insert("path", &format!("/{}", dir));
+        context.insert("parent", &(!dir.is_empty()).then(|| {
+            let parent = dir.trim_end_matches('/').rsplit_once('/').map(|(p, _)| p).unwrap_or("");
+            encode_path(&if parent.is_empty() { String::new() } else { format!("{}/", parent) })
+        }));
+        context.insert("entries", &entries);
+        context.insert("sort", sort);
+        context.insert("order", if desc { "desc" } else { "asc" });
+        context.insert("csp_nonce", &csp_nonce(req));
+
+        match template.render("autoindex.html", &context) {
+            Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+            Err(err) => {
+                eprintln!("Template rendering error: {}", err);
+                HttpResponse::InternalServerError().body("Template rendering error")
+            }
+        }
+    }
+}
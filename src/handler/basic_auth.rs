@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use super::client_info::ClientInfo;
+use super::rate_limit::RateLimiter;
+use crate::sys::init::{BasicAuthConfig, RateLimitConfig};
+use crate::sys::privilege::current_path;
+
+// htpasswdファイルの更新日時を確認する間隔
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+// 認証に成功したAuthorizationヘッダーを覚えておく最大数 (bcryptなどを毎回計算しない)
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+/// htpasswdファイルの内容 (ユーザー名 -> ハッシュ)
+struct Htpasswd {
+    path: String,
+    users: HashMap<String, String>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+    // 認証済みのAuthorizationヘッダーのSHA-256 -> ユーザー名 (再読み込みで捨てる)
+    verified: HashMap<[u8; 32], String>,
+}
+
+impl Htpasswd {
+    fn load(path: &str) -> Htpasswd {
+        let modified = fs::metadata(current_path(path)).and_then(|m| m.modified()).ok();
+        let users = match fs::read_to_string(current_path(path)) {
+            Ok(content) => parse_htpasswd(path, &content),
+            Err(err) => {
+                eprintln!("Failed to read htpasswd {}: {}", path, err);
+                HashMap::new()
+            }
+        };
+        println!("htpasswd loaded: {} ({} users)", path, users.len());
+        Htpasswd { path: path.to_string(), users, modified, checked: Instant::now(), verified: HashMap::new() }
+    }
+}
+
+/// 一定間隔でhtpasswdファイルの更新日時を確認し、変わっていれば読み直す
+fn reload_if_changed(htpasswd: &RwLock<Htpasswd>) {
+    if htpasswd.read().unwrap().checked.elapsed() < RELOAD_CHECK_INTERVAL {
+        return;
+    }
+    let mut htpasswd = htpasswd.write().unwrap();
+    if htpasswd.checked.elapsed() < RELOAD_CHECK_INTERVAL {
+        return;
+    }
+    htpasswd.checked = Instant::now();
+    if fs::metadata(current_path(&htpasswd.path)).and_then(|m| m.modified()).ok() != htpasswd.modified {
+        *htpasswd = Htpasswd::load(&htpasswd.path);
+    }
+}
+
+fn parse_htpasswd(path: &str, content: &str) -> HashMap<String, String> {
+    let mut users = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((user, hash)) = line.split_once(':') else {
+            eprintln!("htpasswd {}:{}: malformed line", path, i + 1);
+            continue;
+        };
+        if !is_supported(hash) {
+            eprintln!("htpasswd {}:{}: unsupported hash for {} (use bcrypt, argon2 or {{SHA}})", path, i + 1, user);
+            continue;
+        }
+        users.insert(user.to_string(), hash.to_string());
+    }
+    users
+}
+
+fn is_supported(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$", "$argon2", "{SHA}"].iter().any(|p| hash.starts_with(p))
+}
+
+/// パスワードをhtpasswdのハッシュと照合する
+fn verify_password(password: &str, hash: &str) -> bool {
+    if let Some(expected) = hash.strip_prefix("{SHA}") {
+        let actual = STANDARD.encode(Sha1::digest(password.as_bytes()));
+        return actual.as_bytes().ct_eq(expected.as_bytes()).into();
+    }
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash)
+            .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok());
+    }
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+struct Rule {
+    prefix: String,
+    realm: String,
+    htpasswd: RwLock<Htpasswd>,
+}
+
+pub enum AuthResult {
+    // 保護されていないか認証に成功した
+    Allowed,
+    // 401 (WWW-Authenticateの値)
+    Unauthorized(String),
+    // 失敗が多すぎる (Retry-Afterの秒数)
+    TooManyFailures(u64),
+}
+
+/// パスのプレフィックスごとのBasic認証
+pub struct BasicAuth {
+    rules: Vec<Rule>,
+    failures: RateLimiter,
+    // 存在しないユーザーでも同じだけ時間をかけるためのハッシュ
+    dummy_hash: String,
+}
+
+impl BasicAuth {
+    pub fn new(configs: &[BasicAuthConfig], failure_limit: &RateLimitConfig) -> Self {
+        let rules = configs.iter()
+            .map(|config| Rule {
+                prefix: match config.path.trim_matches('/') {
+                    "" => String::new(),
+                    path => format!("{}/", path),
+                },
+                realm: config.realm.clone(),
+                htpasswd: RwLock::new(Htpasswd::load(&config.htpasswd)),
+            })
+            .collect();
+        let dummy_hash = if configs.is_empty() {
+            String::new()
+        } else {
+            bcrypt::hash(rand::random::<[u8; 16]>(), bcrypt::DEFAULT_COST).expect("Failed to create dummy hash")
+        };
+        BasicAuth { rules, failures: RateLimiter::new(failure_limit), dummy_hash }
+    }
+
+    /// 最も長く一致したプレフィックスの設定 ("staging" は "staging/" にも一致させる)
+    fn rule_for(&self, path: &str) -> Option<&Rule> {
+        self.rules.iter()
+            .filter(|rule| path.starts_with(&rule.prefix) || format!("{}/", path) == rule.prefix)
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    /// 認証が必要なパスか (静的書き出しではこれらを除く)
+    pub fn is_protected(&self, path: &str) -> bool {
+        self.rule_for(path).is_some()
+    }
+
+    /// ディレクトリの一覧からpathを隠すか (dirと別の設定で保護されている)
+    pub fn hides(&self, dir: &str, path: &str) -> bool {
+        let prefix = |path| self.rule_for(path).map(|rule| rule.prefix.as_str());
+        prefix(path).is_some() && prefix(path) != prefix(dir)
+    }
+
+    /// 正規化済みのパス (先頭 "/" なし) に対するリクエストを認証する
+    pub async fn check(&self, req: &HttpRequest, path: &str) -> AuthResult {
+        let Some(rule) = self.rule_for(path) else {
+            return AuthResult::Allowed;
+        };
+        let unauthorized = || AuthResult::Unauthorized(format!("Basic realm=\"{}\", charset=\"UTF-8\"", rule.realm));
+
+        let ip = ClientInfo::get(req).ip;
+        if let Some(retry_after) = self.failures.retry_after(ip, &rule.prefix) {
+            return AuthResult::TooManyFailures(retry_after);
+        }
+
+        let Some(authorization) = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+            return unauthorized();
+        };
+        if self.verify(rule, authorization).await {
+            return AuthResult::Allowed;
+        }
+
+        log::debug!("Basic auth failed: {} {}", ip, path);
+        self.failures.consume(ip, &rule.prefix);
+        unauthorized()
+    }
+
+    async fn verify(&self, rule: &Rule, authorization: &str) -> bool {
+        reload_if_changed(&rule.htpasswd);
+
+        let key: [u8; 32] = Sha256::digest(authorization.as_bytes()).into();
+        {
+            let htpasswd = rule.htpasswd.read().unwrap();
+            if htpasswd.verified.get(&key).is_some_and(|user| htpasswd.users.contains_key(user)) {
+                return true;
+            }
+        }
+
+        let Some(credentials) = authorization.strip_prefix("Basic ")
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+        else {
+            return false;
+        };
+        let Some((user, password)) = credentials.split_once(':') else {
+            return false;
+        };
+
+        let hash = rule.htpasswd.read().unwrap().users.get(user).cloned();
+        let known = hash.is_some();
+        let hash = hash.unwrap_or_else(|| self.dummy_hash.clone());
+        let password = password.to_string();
+        // bcryptやArgon2は重いのでワーカーを止めないようにブロッキング用のスレッドで照合する
+        let matched = web::block(move || verify_password(&password, &hash)).await.unwrap_or(false);
+        let ok = known && matched;
+        if ok {
+            let mut htpasswd = rule.htpasswd.write().unwrap();
+            if htpasswd.verified.len() >= VERIFIED_CACHE_SIZE {
+                htpasswd.verified.clear();
+            }
+            htpasswd.verified.insert(key, user.to_string());
+        }
+        ok
+    }
+}
+
+impl AuthResult {
+    /// 認証できなかった場合のレスポンス (ページはErrHandlerが描画する)
+    pub fn into_response(self) -> Option<HttpResponse> {
+        match self {
+            AuthResult::Allowed => None,
+            AuthResult::Unauthorized(challenge) => Some(
+                HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, challenge))
+                    .finish(),
+            ),
+            AuthResult::TooManyFailures(retry_after) => Some(
+                HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after))
+                    .finish(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    // "secret" のSHA-1
+    const SHA_SECRET: &str = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=";
+
+    fn authorization(user: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password)))
+    }
+
+    #[test]
+    fn parses_htpasswd() {
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        let content = format!(
+            "# comment\n\nalice:{}\n  bob:{}  \nmalformed\ncarol:$apr1$abc$def\ndave:plain\n",
+            bcrypt, SHA_SECRET,
+        );
+        let users = parse_htpasswd("test", &content);
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["alice"], bcrypt);
+        assert_eq!(users["bob"], SHA_SECRET);
+    }
+
+    #[test]
+    fn verifies_passwords() {
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_password("secret", &bcrypt));
+        assert!(!verify_password("Secret", &bcrypt));
+        // htpasswdが出力する $2y$ も受け付ける
+        assert!(verify_password("secret", &bcrypt.replacen("$2b$", "$2y$", 1)));
+
+        assert!(verify_password("secret", SHA_SECRET));
+        assert!(!verify_password("secret2", SHA_SECRET));
+        assert!(!verify_password("secret", "{SHA}"));
+        assert!(!verify_password("secret", "$2b$broken"));
+        assert!(!verify_password("secret", "$argon2id$broken"));
+    }
+
+    #[actix_web::test]
+    async fn checks_protected_paths() {
+        let path = std::env::temp_dir().join(format!("basic-auth-test-{}", std::process::id()));
+        fs::write(&path, format!("alice:{}\nbob:{}\n", bcrypt::hash("secret", 4).unwrap(), SHA_SECRET)).unwrap();
+        let config = BasicAuthConfig {
+            path: "/staging/".to_string(),
+            realm: "Staging".to_string(),
+            htpasswd: path.to_string_lossy().into_owned(),
+        };
+        let failure_limit = RateLimitConfig { burst: 2, per_second: 0.001, groups: Vec::new(), max_clients: 10 };
+        let auth = BasicAuth::new(&[config], &failure_limit);
+        fs::remove_file(&path).unwrap();
+
+        assert!(auth.is_protected("staging"));
+        assert!(auth.is_protected("staging/a.html"));
+        assert!(!auth.is_protected("stagingx/a.html"));
+
+        let check = |authorization: Option<String>, path: &'static str| {
+            let mut req = TestRequest::get().peer_addr("192.0.2.1:40000".parse().unwrap());
+            if let Some(authorization) = authorization {
+                req = req.insert_header((header::AUTHORIZATION, authorization));
+            }
+            let req = req.to_http_request();
+            let auth = &auth;
+            async move { auth.check(&req, path).await }
+        };
+        assert!(matches!(check(None, "index.html").await, AuthResult::Allowed));
+        assert!(matches!(check(Some(authorization("alice", "secret")), "staging/a.html").await, AuthResult::Allowed));
+        assert!(matches!(check(Some(authorization("bob", "secret")), "staging/").await, AuthResult::Allowed));
+        // 認証済みのヘッダーはキャッシュから照合する
+        assert!(matches!(check(Some(authorization("alice", "secret")), "staging/b.html").await, AuthResult::Allowed));
+
+        match check(None, "staging/a.html").await {
+            AuthResult::Unauthorized(challenge) => assert_eq!(challenge, "Basic realm=\"Staging\", charset=\"UTF-8\""),
+            _ => panic!("expected 401"),
+        }
+        // 失敗はburstまで
+        assert!(matches!(check(Some(authorization("alice", "wrong")), "staging/a.html").await, AuthResult::Unauthorized(_)));
+        assert!(matches!(check(Some(authorization("bob", "wrong")), "staging/a.html").await, AuthResult::Unauthorized(_)));
+        assert!(matches!(check(Some(authorization("alice", "secret")), "staging/a.html").await, AuthResult::TooManyFailures(_)));
+    }
+}
@@ -0,0 +1,303 @@
+use std::any::Any;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Extensions, ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::Next,
+    rt::net::TcpStream,
+    web, HttpResponse,
+};
+use glob::Pattern;
+use nix::sys::socket::{shutdown, Shutdown};
+use percent_encoding::percent_decode_str;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use super::client_info::ClientInfo;
+use crate::sys::app_set::AppSet;
+use crate::sys::init::{BlockAction, BlockPath, BlockRule};
+
+/// 接続のソケット (drop で閉じるために接続ごとに覚えておく)
+pub struct ConnectionFd(pub RawFd);
+
+/// HttpServer::on_connect に渡す
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = conn.downcast_ref::<TcpStream>() {
+        data.insert(ConnectionFd(stream.as_raw_fd()));
+    }
+}
+
+enum PathMatcher {
+    Glob(Pattern),
+    Regex(Regex),
+}
+
+impl PathMatcher {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatcher::Glob(pattern) => pattern.matches(path),
+            PathMatcher::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    path: Option<PathMatcher>,
+    user_agent: Option<Regex>,
+    methods: Vec<String>,
+    max_query_length: Option<usize>,
+    action: BlockAction,
+    hits: AtomicU64,
+}
+
+impl Rule {
+    /// 指定された条件がすべて一致するか
+    fn matches(&self, req: &ServiceRequest, decoded_path: &str) -> bool {
+        let path_ok = self.path.as_ref()
+            .is_none_or(|p| p.matches(req.path()) || p.matches(decoded_path));
+        let user_agent_ok = self.user_agent.as_ref().is_none_or(|regex| {
+            let user_agent = req.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok()).unwrap_or("");
+            regex.is_match(user_agent)
+        });
+        let method_ok = self.methods.is_empty() || self.methods.iter().any(|m| m == req.method().as_str());
+        let query_ok = self.max_query_length.is_none_or(|max| req.query_string().len() > max);
+        path_ok && user_agent_ok && method_ok && query_ok
+    }
+}
+
+/// ルールごとのブロック件数 (/_admin/block-rules.json)
+#[derive(Serialize)]
+pub struct RuleHits {
+    pub name: String,
+    pub action: String,
+    pub hits: u64,
+}
+
+/// スキャナーや攻撃の探索リクエストをRouterより前に遮断するルール (最初に一致したもの)
+pub struct BlockRules {
+    rules: Vec<Rule>,
+    tarpit_max: usize,
+    tarpitting: AtomicUsize,
+}
+
+impl BlockRules {
+    pub fn new(configs: &[BlockRule], tarpit_max: usize) -> Result<Self, String> {
+        let rules = configs.iter()
+            .map(|config| {
+                let path = match &config.path {
+                    Some(BlockPath::Glob(glob)) => Some(PathMatcher::Glob(
+                        Pattern::new(glob).map_err(|e| format!("block rule {}: invalid glob {}: {}", config.name, glob, e))?,
+                    )),
+                    Some(BlockPath::Regex(regex)) => Some(PathMatcher::Regex(
+                        Regex::new(regex).map_err(|e| format!("block rule {}: invalid regex {}: {}", config.name, regex, e))?,
+                    )),
+                    None => None,
+                };
+                let user_agent = config.user_agent.as_ref()
+                    .map(|ua| {
+                        RegexBuilder::new(ua).case_insensitive(true).build()
+                            .map_err(|e| format!("block rule {}: invalid user agent regex {}: {}", config.name, ua, e))
+                    })
+                    .transpose()?;
+                if path.is_none() && user_agent.is_none() && config.methods.is_empty() && config.max_query_length.is_none() {
+                    return Err(format!("block rule {}: no conditions", config.name));
+                }
+                Ok(Rule {
+                    name: config.name.clone(),
+                    path,
+                    user_agent,
+                    methods: config.methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+                    max_query_length: config.max_query_length,
+                    action: config.action,
+                    hits: AtomicU64::new(0),
+                })
+            })
+            .collect::<Result<Vec<Rule>, String>>()?;
+        let names: Vec<&str> = rules.iter().map(|rule| rule.name.as_str()).collect();
+        println!("Block rules active: {}", names.join(", "));
+        Ok(BlockRules { rules, tarpit_max, tarpitting: AtomicUsize::new(0) })
+    }
+
+    fn rule_for(&self, req: &ServiceRequest) -> Option<&Rule> {
+        let decoded_path = percent_decode_str(req.path()).decode_utf8_lossy();
+        self.rules.iter().find(|rule| rule.matches(req, &decoded_path))
+    }
+
+    pub fn snapshot(&self) -> Vec<RuleHits> {
+        self.rules.iter()
+            .map(|rule| RuleHits {
+                name: rule.name.clone(),
+                action: match rule.action {
+                    BlockAction::Drop => "drop".to_string(),
+                    BlockAction::Forbidden => "403".to_string(),
+                    BlockAction::Tarpit(secs) => format!("tarpit {}s", secs),
+                },
+                hits: rule.hits.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// 同時に遅延させている接続の数を数える
+struct TarpitGuard<'a>(&'a AtomicUsize);
+
+impl Drop for TarpitGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// ルールに一致したリクエストは接続を切るか、403を返すか、遅延させてから403を返す
+/// エラーページを描画せずログにも残さないように最も外側に置く
+pub async fn middleware<B: MessageBody>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let app_set = req.app_data::<web::Data<AppSet>>().cloned();
+    let Some(block_rules) = app_set.as_ref().and_then(|a| a.block_rules.as_ref()) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(rule) = block_rules.rule_for(&req) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    rule.hits.fetch_add(1, Ordering::Relaxed);
+
+    let mut action = rule.action;
+    if let BlockAction::Tarpit(secs) = action {
+        // 遅延中の接続が多すぎる場合は切る
+        if block_rules.tarpitting.fetch_add(1, Ordering::Relaxed) >= block_rules.tarpit_max {
+            block_rules.tarpitting.fetch_sub(1, Ordering::Relaxed);
+            action = BlockAction::Drop;
+        } else {
+            let _guard = TarpitGuard(&block_rules.tarpitting);
+            actix_web::rt::time::sleep(Duration::from_secs(secs)).await;
+        }
+    }
+
+    if let BlockAction::Drop = action {
+        match req.conn_data::<ConnectionFd>().map(|fd| shutdown(fd.0, Shutdown::Both)) {
+            // ソケットを閉じたのでレスポンスは送られずに接続が切れる (ステータスはnginxと同じ444にしておく)
+            Some(Ok(())) => {
+                let response = HttpResponse::build(StatusCode::from_u16(444).unwrap()).force_close().finish();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            Some(Err(err)) => {
+                eprintln!("Failed to drop connection from {}: {}, responding 403", ClientInfo::get(req.request()).ip, err);
+            }
+            None => eprintln!("Block rule {}: connection socket unknown, responding 403", rule.name),
+        }
+    }
+
+    let response = HttpResponse::Forbidden().force_close().finish();
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware::from_fn, App, HttpServer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::sys::init::{default_block_rules, AppConfig};
+
+    fn matched(rules: &BlockRules, req: TestRequest) -> Option<String> {
+        rules.rule_for(&req.to_srv_request()).map(|rule| rule.name.clone())
+    }
+
+    fn rule(name: &str, path: Option<BlockPath>) -> BlockRule {
+        BlockRule { name: name.to_string(), path, user_agent: None, methods: Vec::new(), max_query_length: None, action: BlockAction::Forbidden }
+    }
+
+    #[test]
+    fn default_rules_match_probes() {
+        let rules = BlockRules::new(&default_block_rules(), 1).unwrap();
+        let get = |uri: &str| matched(&rules, TestRequest::get().uri(uri));
+        assert_eq!(get("/%2e%2e/etc/passwd").as_deref(), Some("path-traversal"));
+        assert_eq!(get("/.env").as_deref(), Some("dotfiles"));
+        assert_eq!(get("/a/.git/config").as_deref(), Some("dotfiles"));
+        assert_eq!(get("/wp-login.php").as_deref(), Some("wordpress"));
+        assert_eq!(get("/blog/index.php").as_deref(), Some("php"));
+        assert_eq!(get("/x?q=1"), None);
+        assert_eq!(get(&format!("/?q={}", "a".repeat(2049))).as_deref(), Some("long-query"));
+        assert_eq!(get("/.well-known/security.txt"), None);
+        assert_eq!(get("/environment.html"), None);
+        assert_eq!(get("/"), None);
+
+        let ua = matched(&rules, TestRequest::get().uri("/").insert_header((header::USER_AGENT, "Mozilla/5.0 SQLMap/1.7")));
+        assert_eq!(ua.as_deref(), Some("scanner-user-agents"));
+        let method = matched(&rules, TestRequest::default().method("TRACE".parse().unwrap()).uri("/"));
+        assert_eq!(method.as_deref(), Some("unusual-methods"));
+    }
+
+    #[test]
+    fn all_conditions_must_match() {
+        let configs = [
+            BlockRule {
+                methods: vec!["post".to_string()],
+                ..rule("post-cgi", Some(BlockPath::Glob("/cgi/*".to_string())))
+            },
+            rule("encoded", Some(BlockPath::Regex("^/secret dir/".to_string()))),
+        ];
+        let rules = BlockRules::new(&configs, 1).unwrap();
+        assert_eq!(matched(&rules, TestRequest::post().uri("/cgi/a/b")).as_deref(), Some("post-cgi"));
+        assert_eq!(matched(&rules, TestRequest::get().uri("/cgi/a")), None);
+        // デコードしたパスにも一致させる
+        assert_eq!(matched(&rules, TestRequest::get().uri("/secret%20dir/x")).as_deref(), Some("encoded"));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(BlockRules::new(&[rule("empty", None)], 1).err().unwrap().contains("no conditions"));
+        assert!(BlockRules::new(&[rule("glob", Some(BlockPath::Glob("[".to_string())))], 1).is_err());
+        assert!(BlockRules::new(&[rule("regex", Some(BlockPath::Regex("(".to_string())))], 1).is_err());
+    }
+
+    #[actix_web::test]
+    async fn forbidden_without_error_page() {
+        let app_set = web::Data::new(AppSet::new(AppConfig::new()).await);
+        let app = init_service(App::new().wrap(from_fn(middleware)).app_data(app_set.clone()).default_service(web::to(HttpResponse::Ok))).await;
+
+        let res = call_service(&app, TestRequest::get().uri("/index.php").to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // 接続のソケットが分からなければDropも403にする
+        let res = call_service(&app, TestRequest::get().uri("/.env").to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = call_service(&app, TestRequest::get().uri("/index.html").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let hits = app_set.block_rules.as_ref().unwrap().snapshot();
+        let hits_of = |name: &str| hits.iter().find(|h| h.name == name).unwrap().hits;
+        assert_eq!((hits_of("php"), hits_of("dotfiles")), (1, 1));
+    }
+
+    #[actix_web::test]
+    async fn drop_closes_connection_without_response() {
+        let app_set = web::Data::new(AppSet::new(AppConfig::new()).await);
+        let server = HttpServer::new(move || App::new().wrap(from_fn(middleware)).app_data(app_set.clone()).default_service(web::to(HttpResponse::Ok)))
+            .on_connect(on_connect)
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let request = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response).await;
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let dropped = request("/.env").await;
+        let forbidden = request("/index.php").await;
+        handle.stop(false).await;
+
+        assert_eq!(dropped, "");
+        assert!(forbidden.starts_with("HTTP/1.1 403"), "{}", forbidden);
+    }
+}
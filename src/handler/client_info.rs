@@ -0,0 +1,201 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use actix_web::{web, HttpMessage, HttpRequest};
+use ipnet::IpNet;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::sys::app_set::AppSet;
+
+// テンプレートの client.ip と client.scheme。描画済みのページはレスポンス時にリクエストの値へ置き換える
+pub const CLIENT_IP_PLACEHOLDER: &str = "__client_ip_placeholder__";
+pub const CLIENT_SCHEME_PLACEHOLDER: &str = "__client_scheme_placeholder__";
+
+/// 信頼できるプロキシを考慮して解決したクライアント情報
+/// ログ、レート制限、テンプレートなどは必ずここから取得する
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub scheme: String,
+}
+
+impl ClientInfo {
+    /// リクエストのクライアント情報を取得する (一度解決したらextensionsにキャッシュする)
+    pub fn get(req: &HttpRequest) -> ClientInfo {
+        if let Some(info) = req.extensions().get::<ClientInfo>() {
+            return info.clone();
+        }
+
+        let info = match req.app_data::<web::Data<AppSet>>() {
+            Some(app_set) => ClientInfo::resolve(req, &app_set.app_config.trusted_proxies),
+            None => ClientInfo::resolve(req, &[]),
+        };
+        req.extensions_mut().insert(info.clone());
+        info
+    }
+
+    /// テンプレートの client (値はプレースホルダー)
+    pub fn placeholder_context() -> Value {
+        json!({ "ip": CLIENT_IP_PLACEHOLDER, "scheme": CLIENT_SCHEME_PLACEHOLDER })
+    }
+
+    /// 描画したHTMLのプレースホルダーをリクエストの値に置き換える (含まなければNone)
+    pub fn inject(req: &HttpRequest, body: &str) -> Option<String> {
+        if !body.contains(CLIENT_IP_PLACEHOLDER) && !body.contains(CLIENT_SCHEME_PLACEHOLDER) {
+            return None;
+        }
+        let info = ClientInfo::get(req);
+        Some(body.replace(CLIENT_IP_PLACEHOLDER, &info.ip.to_string()).replace(CLIENT_SCHEME_PLACEHOLDER, &info.scheme))
+    }
+
+    pub fn resolve(req: &HttpRequest, trusted_proxies: &[IpNet]) -> ClientInfo {
+        let peer = req.peer_addr()
+            .map(|addr| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let default_scheme = if req.app_config().secure() { "https" } else { "http" };
+        let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+        // 直接のピアが信頼できなければヘッダーは一切見ない
+        if !is_trusted(&peer) {
+            return ClientInfo { ip: peer, scheme: default_scheme.to_string() };
+        }
+
+        // (アドレス, プロトコル) のチェーン。左がクライアント側
+        let chain: Vec<(IpAddr, Option<String>)> = if let Some(forwarded) = header(req, "Forwarded") {
+            parse_forwarded(&forwarded)
+        } else if let Some(xff) = header(req, "X-Forwarded-For") {
+            xff.split(',').filter_map(parse_node).map(|ip| (ip, None)).collect()
+        } else if let Some(cf) = header(req, "Cf-Connecting-Ip") {
+            parse_node(&cf).map(|ip| vec![(ip, None)]).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        // 右から辿り、最初に見つかった信頼できないアドレスをクライアントとする
+        let mut client = (peer, None);
+        for (ip, proto) in chain.into_iter().rev() {
+            client = (ip, proto);
+            if !is_trusted(&ip) {
+                break;
+            }
+        }
+
+        let scheme = client.1
+            .or_else(|| header(req, "X-Forwarded-Proto").map(|p| p.trim().to_string()))
+            .map(|p| p.to_ascii_lowercase())
+            .filter(|p| p == "http" || p == "https")
+            .unwrap_or_else(|| default_scheme.to_string());
+
+        ClientInfo { ip: client.0, scheme }
+    }
+}
+
+/// 同じ名前のヘッダーが複数行ある場合は "," で繋ぐ
+/// (最初の行だけ読むと、クライアントが送った行から辿ってしまい、プロキシが追加した行を見落とす)
+fn header(req: &HttpRequest, name: &str) -> Option<String> {
+    let values: Vec<&str> = req.headers().get_all(name).filter_map(|v| v.to_str().ok()).collect();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+/// RFC 7239 の Forwarded ヘッダーを解析する
+fn parse_forwarded(value: &str) -> Vec<(IpAddr, Option<String>)> {
+    value.split(',')
+        .filter_map(|element| {
+            let mut ip = None;
+            let mut proto = None;
+            for pair in element.split(';') {
+                if let Some((key, val)) = pair.split_once('=') {
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "for" => ip = parse_node(val),
+                        "proto" => proto = Some(val.trim().trim_matches('"').to_string()),
+                        _ => {}
+                    }
+                }
+            }
+            ip.map(|ip| (ip, proto))
+        })
+        .collect()
+}
+
+/// "1.2.3.4", "1.2.3.4:80", "[::1]:80", "\"[::1]\"" などからIPアドレスを取り出す
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    if let Ok(ip) = value.parse() {
+        return Some(ip);
+    }
+    value.rsplit_once(':')
+        .and_then(|(host, _)| host.parse::<Ipv4Addr>().ok())
+        .map(IpAddr::V4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn resolve(headers: &[(&str, &str)]) -> ClientInfo {
+        let mut req = TestRequest::default().peer_addr("127.0.0.1:40000".parse().unwrap());
+        for header in headers {
+            req = req.append_header(*header);
+        }
+        ClientInfo::resolve(&req.to_http_request(), &["127.0.0.0/8".parse().unwrap(), "10.0.0.0/8".parse().unwrap()])
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_headers() {
+        let req = TestRequest::default()
+            .peer_addr("198.51.100.1:40000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.9"))
+            .to_http_request();
+        assert_eq!(ClientInfo::resolve(&req, &["127.0.0.0/8".parse().unwrap()]).ip, ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn rightmost_untrusted_address() {
+        assert_eq!(resolve(&[("X-Forwarded-For", "1.2.3.4, 203.0.113.9, 10.0.0.2")]).ip, ip("203.0.113.9"));
+        assert_eq!(resolve(&[("X-Forwarded-For", "10.0.0.3, 10.0.0.2")]).ip, ip("10.0.0.3"));
+        assert_eq!(resolve(&[]).ip, ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn multiple_header_lines_are_joined() {
+        // クライアントが送った行の後にプロキシが行を追加した場合
+        let headers = [("X-Forwarded-For", "1.2.3.4"), ("X-Forwarded-For", "203.0.113.9")];
+        assert_eq!(resolve(&headers).ip, ip("203.0.113.9"));
+
+        let headers = [("Forwarded", "for=1.2.3.4;proto=https"), ("Forwarded", "for=203.0.113.9;proto=http")];
+        let info = resolve(&headers);
+        assert_eq!(info.ip, ip("203.0.113.9"));
+        assert_eq!(info.scheme, "http");
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let headers = [("X-Forwarded-For", "1.2.3.4"), ("Forwarded", "for=\"[2001:db8::1]:443\";proto=https")];
+        let info = resolve(&headers);
+        assert_eq!(info.ip, ip("2001:db8::1"));
+        assert_eq!(info.scheme, "https");
+    }
+
+    #[test]
+    fn scheme_from_forwarded_proto() {
+        assert_eq!(resolve(&[("X-Forwarded-For", "203.0.113.9"), ("X-Forwarded-Proto", "HTTPS")]).scheme, "https");
+        assert_eq!(resolve(&[("X-Forwarded-Proto", "javascript")]).scheme, "http");
+    }
+
+    #[test]
+    fn parse_node_forms() {
+        assert_eq!(parse_node(" 203.0.113.9:8080 "), Some(ip("203.0.113.9")));
+        assert_eq!(parse_node("\"[::1]:80\""), Some(ip("::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+}
@@ -0,0 +1,328 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web, HttpResponse,
+};
+use glob::Pattern;
+use regex::Regex;
+
+use super::path::normalize_path;
+use crate::sys::app_set::AppSet;
+use crate::sys::init::{CorsOrigin, CorsPolicy};
+
+// プリフライトなしで送れるメソッド
+const SAFELISTED_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+
+enum OriginMatcher {
+    Any,
+    Exact(String),
+    // "https://*.example.com" の "https://" と ".example.com"
+    Subdomain(String, String),
+    Regex(Regex),
+}
+
+impl OriginMatcher {
+    fn new(origin: &CorsOrigin) -> Result<Self, String> {
+        Ok(match origin {
+            CorsOrigin::Any => OriginMatcher::Any,
+            CorsOrigin::Exact(origin) => OriginMatcher::Exact(origin.trim_end_matches('/').to_ascii_lowercase()),
+            CorsOrigin::Subdomain(pattern) => {
+                let (scheme, domain) = pattern.to_ascii_lowercase().split_once("://*.")
+                    .map(|(scheme, domain)| (format!("{}://", scheme), format!(".{}", domain.trim_end_matches('/'))))
+                    .ok_or_else(|| format!("cors: subdomain origin must look like https://*.example.com: {}", pattern))?;
+                OriginMatcher::Subdomain(scheme, domain)
+            }
+            // オリジン全体に一致させる ("https://app\.example\.com" が "https://app.example.com.evil" に一致しないように)
+            CorsOrigin::Regex(pattern) => OriginMatcher::Regex(
+                Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| format!("cors: invalid origin regex {}: {}", pattern, e))?,
+            ),
+        })
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginMatcher::Any => true,
+            OriginMatcher::Exact(expected) => origin.eq_ignore_ascii_case(expected),
+            OriginMatcher::Subdomain(scheme, domain) => {
+                let origin = origin.to_ascii_lowercase();
+                // サブドメイン部分は空でないラベルをホスト名に使える文字で "." 区切りにしたもの (ドメイン自身は含めない)
+                origin.strip_prefix(scheme.as_str())
+                    .and_then(|rest| rest.strip_suffix(domain.as_str()))
+                    .is_some_and(|sub| {
+                        sub.split('.').all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+                    })
+            }
+            OriginMatcher::Regex(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+struct Policy {
+    pattern: Pattern,
+    origins: Vec<OriginMatcher>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    expose_headers: String,
+    credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl Policy {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|o| o.matches(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        SAFELISTED_METHODS.contains(&method) || self.methods.iter().any(|m| m == method)
+    }
+
+    /// "*" は資格情報付きのリクエストには使えない (ブラウザがワイルドカードとして扱わない)
+    fn allows_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|h| h.eq_ignore_ascii_case(name) || (h == "*" && !self.credentials))
+    }
+
+    /// Access-Control-Allow-Origin の値 (任意のオリジンを許可するなら "*")
+    fn allow_origin_value(&self, origin: &str) -> String {
+        // 資格情報付きのAnyはCors::newで拒否している
+        if self.origins.iter().any(|o| matches!(o, OriginMatcher::Any)) {
+            "*".to_string()
+        } else {
+            origin.to_string()
+        }
+    }
+}
+
+/// パスのパターンごとのCORSポリシー (最初に一致したもの)
+pub struct Cors {
+    policies: Vec<Policy>,
+    unicode_nfc: bool,
+}
+
+impl Cors {
+    pub fn new(configs: &[CorsPolicy], unicode_nfc: bool) -> Result<Self, String> {
+        let policies = configs.iter()
+            .map(|config| {
+                // どのオリジンにも資格情報付きのレスポンスを読ませることになる
+                if config.credentials && config.origins.iter().any(|o| matches!(o, CorsOrigin::Any)) {
+                    return Err(format!("cors: {}: any origin cannot be combined with credentials", config.path));
+                }
+                Ok(Policy {
+                    pattern: Pattern::new(&config.path)
+                        .map_err(|e| format!("cors: invalid path pattern {}: {}", config.path, e))?,
+                    origins: config.origins.iter().map(OriginMatcher::new).collect::<Result<_, String>>()?,
+                    methods: config.methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+                    headers: config.headers.clone(),
+                    expose_headers: config.expose_headers.join(", "),
+                    credentials: config.credentials,
+                    max_age: config.max_age,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Cors { policies, unicode_nfc })
+    }
+
+    /// 正規化したパスに一致する最初のポリシー
+    fn policy_for(&self, path: &str) -> Option<&Policy> {
+        let path = normalize_path(path, self.unicode_nfc).map(|p| format!("/{}", p)).ok()?;
+        self.policies.iter().find(|policy| policy.pattern.matches(&path))
+    }
+}
+
+/// プリフライトにはRouterに渡さず応答し、それ以外のレスポンスにはCORSヘッダーを付ける
+/// エラーページもブラウザから読めるようにErrorHandlersの外側に置く
+pub async fn middleware<B: MessageBody>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let app_set = req.app_data::<web::Data<AppSet>>().cloned();
+    let Some(cors) = app_set.as_ref().and_then(|a| a.cors.as_ref()) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(policy) = cors.policy_for(req.path()) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let origin = req.headers().get(header::ORIGIN).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+
+    let request_method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD).and_then(|v| v.to_str().ok());
+    if let (true, Some(origin), Some(request_method)) = (req.method() == Method::OPTIONS, &origin, request_method) {
+        let response = preflight(policy, origin, request_method, &req);
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let mut res = next.call(req).await?;
+    let headers = res.headers_mut();
+    headers.append(header::VARY, header::HeaderValue::from_static("Origin"));
+    if let Some(origin) = origin.filter(|o| policy.allows_origin(o)) {
+        let mut set = |name, value: &str| {
+            if let Ok(value) = header::HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        set(header::ACCESS_CONTROL_ALLOW_ORIGIN, &policy.allow_origin_value(&origin));
+        if policy.credentials {
+            set(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if !policy.expose_headers.is_empty() {
+            set(header::ACCESS_CONTROL_EXPOSE_HEADERS, &policy.expose_headers);
+        }
+    }
+    Ok(res.map_into_left_body())
+}
+
+/// 許可しないオリジン/メソッド/ヘッダーのプリフライトには403を返す
+fn preflight(policy: &Policy, origin: &str, request_method: &str, req: &ServiceRequest) -> HttpResponse {
+    let request_headers: Vec<String> = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').map(|h| h.trim().to_ascii_lowercase()).filter(|h| !h.is_empty()).collect())
+        .unwrap_or_default();
+
+    let rejected = if !policy.allows_origin(origin) {
+        Some("origin")
+    } else if !policy.allows_method(request_method) {
+        Some("method")
+    } else if !request_headers.iter().all(|h| policy.allows_header(h)) {
+        Some("headers")
+    } else {
+        None
+    };
+
+    let mut response = match rejected {
+        Some(reason) => {
+            log::debug!("CORS preflight rejected: {} {} from {} ({})", request_method, req.path(), origin, reason);
+            HttpResponse::Forbidden()
+        }
+        None => HttpResponse::NoContent(),
+    };
+    response.append_header((header::VARY, "Origin, Access-Control-Request-Method, Access-Control-Request-Headers"));
+    if rejected.is_some() {
+        return response.finish();
+    }
+
+    response.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, policy.allow_origin_value(origin)));
+    let mut methods = policy.methods.clone();
+    if !methods.iter().any(|m| m == request_method) {
+        methods.push(request_method.to_string());
+    }
+    response.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, methods.join(", ")));
+    if !request_headers.is_empty() {
+        response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, request_headers.join(", ")));
+    }
+    if policy.credentials {
+        response.insert_header((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"));
+    }
+    if let Some(max_age) = policy.max_age {
+        response.insert_header((header::ACCESS_CONTROL_MAX_AGE, max_age));
+    }
+    response.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(origin: CorsOrigin) -> OriginMatcher {
+        OriginMatcher::new(&origin).unwrap()
+    }
+
+    fn policy(origins: Vec<CorsOrigin>, credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            path: "/api/*".to_string(),
+            origins,
+            methods: vec!["put".to_string()],
+            headers: vec!["*".to_string()],
+            expose_headers: Vec::new(),
+            credentials,
+            max_age: None,
+        }
+    }
+
+    #[test]
+    fn exact_origin() {
+        let m = matcher(CorsOrigin::Exact("https://App.example.com/".to_string()));
+        assert!(m.matches("https://app.example.com"));
+        assert!(m.matches("HTTPS://APP.EXAMPLE.COM"));
+        for origin in ["http://app.example.com", "https://app.example.com:8443", "https://app.example.com.evil.test", "https://evilapp.example.com", "null", ""] {
+            assert!(!m.matches(origin), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn subdomain_origin() {
+        let m = matcher(CorsOrigin::Subdomain("https://*.Example.com".to_string()));
+        assert!(m.matches("https://app.example.com"));
+        assert!(m.matches("https://a-1.b.EXAMPLE.com"));
+        for origin in [
+            "https://example.com",
+            "https://.example.com",
+            "https://a..example.com",
+            "https://evilexample.com",
+            "http://app.example.com",
+            "https://app.example.com:8443",
+            "https://app.example.com.evil.test",
+            "https://evil.test/.example.com",
+            "https://evil.test#.example.com",
+            "https://evil.test?.example.com",
+            "https://user@app.example.com",
+            "https://evil.test:.example.com",
+        ] {
+            assert!(!m.matches(origin), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn subdomain_pattern_must_have_wildcard() {
+        assert!(OriginMatcher::new(&CorsOrigin::Subdomain("https://example.com".to_string())).is_err());
+        assert!(OriginMatcher::new(&CorsOrigin::Subdomain("*.example.com".to_string())).is_err());
+    }
+
+    #[test]
+    fn regex_origin_is_anchored() {
+        let m = matcher(CorsOrigin::Regex(r"https://[a-z]+\.example\.com".to_string()));
+        assert!(m.matches("https://app.example.com"));
+        assert!(!m.matches("https://app.example.com.evil.test"));
+        assert!(!m.matches("https://evil.test/https://app.example.com"));
+
+        // 選択肢の一部だけに一致させない
+        let m = matcher(CorsOrigin::Regex(r"https://a\.test|https://b\.test".to_string()));
+        assert!(m.matches("https://a.test"));
+        assert!(m.matches("https://b.test"));
+        assert!(!m.matches("https://a.test.evil.test"));
+        assert!(!m.matches("https://evil.test/https://b.test"));
+
+        assert!(OriginMatcher::new(&CorsOrigin::Regex("(".to_string())).is_err());
+    }
+
+    #[test]
+    fn any_origin_without_credentials() {
+        let cors = Cors::new(&[policy(vec![CorsOrigin::Any, CorsOrigin::Exact("https://a.test".to_string())], false)], false).unwrap();
+        let policy = cors.policy_for("/api/items").unwrap();
+        assert!(policy.allows_origin("https://anything.test"));
+        assert_eq!(policy.allow_origin_value("https://a.test"), "*");
+        assert!(policy.allows_header("x-custom"));
+        assert!(cors.policy_for("/other").is_none());
+    }
+
+    #[test]
+    fn any_origin_with_credentials_is_rejected() {
+        assert!(Cors::new(&[policy(vec![CorsOrigin::Any], true)], false).is_err());
+    }
+
+    #[test]
+    fn credentials_echo_origin_and_list_headers() {
+        let cors = Cors::new(&[policy(vec![CorsOrigin::Exact("https://a.test".to_string())], true)], false).unwrap();
+        let policy = cors.policy_for("/api/items").unwrap();
+        assert_eq!(policy.allow_origin_value("https://a.test"), "https://a.test");
+        // "*" は資格情報付きのリクエストには使えない
+        assert!(!policy.allows_header("x-custom"));
+        assert!(policy.allows_method("PUT"));
+        assert!(policy.allows_method("POST"));
+        assert!(!policy.allows_method("DELETE"));
+    }
+
+    #[test]
+    fn policy_uses_normalized_path() {
+        let cors = Cors::new(&[policy(vec![CorsOrigin::Exact("https://a.test".to_string())], false)], false).unwrap();
+        assert!(cors.policy_for("/static/../api/items").is_some());
+        assert!(cors.policy_for("/api/../static/x").is_none());
+        assert!(cors.policy_for("/../api/items").is_none());
+    }
+}
@@ -0,0 +1,334 @@
+use std::cell::{Cell, RefCell};
+
+use actix_http::h1;
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    cookie::{Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use subtle::ConstantTimeEq;
+
+use super::admin;
+use super::err_page::{ProblemDetail, ProblemSuggestions};
+use super::jwt_auth::JwtClaims;
+use crate::sys::app_set::AppSet;
+use crate::sys::init::CsrfConfig;
+
+/// テンプレートの csrf_token() が返す値。レスポンス時にリクエストのトークンへ置き換える
+pub const CSRF_PLACEHOLDER: &str = "__csrf_token_placeholder__";
+
+// 安全なメソッドしか受け付けないルート (index と error_preview)
+const SAFE_ONLY_PATTERNS: [&str; 2] = ["/{path:.*}", "/err/{statuscode}"];
+
+thread_local! {
+    // リクエストごとに描画しているページのトークンと、csrf_token() が呼ばれたか
+    static RENDERING_TOKEN: RefCell<Option<(String, bool)>> = const { RefCell::new(None) };
+}
+
+/// リクエストのCSRFトークン (double-submit cookie)
+struct CsrfToken {
+    token: String,
+    // Cookieにまだないトークンか
+    new: bool,
+    // ページに埋め込んだか (新しいトークンはその場合だけCookieを発行する)
+    used: Cell<bool>,
+}
+
+/// 安全でないメソッドのリクエストのOrigin/Refererとトークンを検証する
+/// トークンはフォームのフィールドかヘッダーで送り、Cookieの値と一致する必要がある
+/// 検証済みのBearerトークン(JWT)があるリクエストはブラウザが自動で送るものではないので対象外
+/// 管理用エンドポイントもadmin_networksで制限していてフォームから送らないので対象外
+pub async fn middleware<B: MessageBody>(mut req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let app_set = req.app_data::<web::Data<AppSet>>().cloned();
+    let Some(config) = app_set.as_ref().and_then(|a| a.app_config.csrf.as_ref()) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let cookie = req.cookie(&config.cookie_name).map(|c| c.value().to_string()).filter(|v| !v.is_empty());
+    let unsafe_method = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let exempt = req.extensions().get::<JwtClaims>().is_some() || is_admin(req.request());
+
+    if unsafe_method && !exempt && reaches_handler(req.request()) {
+        if let Err(reason) = check_origin(req.request(), config) {
+            return Ok(reject(req, reason));
+        }
+        let submitted = submitted_token(&mut req, config).await?;
+        let valid = match (&cookie, &submitted) {
+            (Some(cookie), Some(submitted)) => bool::from(cookie.as_bytes().ct_eq(submitted.as_bytes())),
+            _ => false,
+        };
+        if !valid {
+            return Ok(reject(req, "CSRF token is missing or invalid"));
+        }
+    }
+
+    req.extensions_mut().insert(CsrfToken {
+        new: cookie.is_none(),
+        token: cookie.unwrap_or_else(|| hex::encode(rand::random::<[u8; 32]>())),
+        used: Cell::new(false),
+    });
+
+    let mut res = next.call(req).await?;
+
+    let issue = res.request().extensions().get::<CsrfToken>()
+        .filter(|t| t.new && t.used.get())
+        .map(|t| t.token.clone());
+    if let Some(token) = issue {
+        // JavaScriptからヘッダーに入れて送れるようにHttpOnlyにはしない
+        let cookie = Cookie::build(config.cookie_name.clone(), token)
+            .path("/")
+            .same_site(SameSite::Strict)
+            .secure(config.secure)
+            .finish();
+        if let Ok(value) = header::HeaderValue::from_str(&cookie.to_string()) {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    Ok(res.map_into_left_body())
+}
+
+/// 安全でないメソッドを受け付けるルートに一致するか
+/// 一致しなければmethod_not_allowedが405を返すので検証しない (403より405を優先する)
+fn reaches_handler(req: &HttpRequest) -> bool {
+    req.match_pattern().is_some_and(|pattern| !SAFE_ONLY_PATTERNS.contains(&pattern.as_str()))
+}
+
+/// 管理用エンドポイントのルートに一致するか
+fn is_admin(req: &HttpRequest) -> bool {
+    req.match_pattern().is_some_and(|pattern| pattern.starts_with(&format!("{}/", admin::SCOPE_PATH)))
+}
+
+/// OriginかRefererがリクエストのホストか設定したオリジンと一致するか (どちらもなければトークンだけで判定する)
+fn check_origin(req: &HttpRequest, config: &CsrfConfig) -> Result<(), &'static str> {
+    let origin = req.headers().get(header::ORIGIN).and_then(|v| v.to_str().ok());
+    let referer = req.headers().get(header::REFERER).and_then(|v| v.to_str().ok());
+    let source = match (origin, referer) {
+        (Some(origin), _) if origin != "null" => origin,
+        (Some(_), _) => return Err("Request origin is not allowed"),
+        (None, Some(referer)) => referer,
+        (None, None) => return Ok(()),
+    };
+
+    let Some((scheme, rest)) = source.split_once("://") else {
+        return Err("Request origin is not allowed");
+    };
+    let host = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let source_origin = format!("{}://{}", scheme, host);
+
+    // プロキシの後ろではスキームが分からないことがあるのでホスト(ポート込み)だけ比べる
+    let own_host = req.headers().get(header::HOST).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let same_host = !own_host.is_empty() && host.eq_ignore_ascii_case(own_host);
+    if same_host || config.trusted_origins.iter().any(|o| o.trim_end_matches('/').eq_ignore_ascii_case(&source_origin)) {
+        Ok(())
+    } else {
+        Err("Request origin is not allowed")
+    }
+}
+
+/// ヘッダーか、urlencodedのフォームのフィールドから送られたトークン
+/// フォームを読んだ場合は後続のハンドラーのためにボディを戻す
+async fn submitted_token(req: &mut ServiceRequest, config: &CsrfConfig) -> Result<Option<String>, actix_web::Error> {
+    if let Some(token) = req.headers().get(config.header_name.as_str()).and_then(|v| v.to_str().ok()) {
+        return Ok(Some(token.to_string()));
+    }
+    if req.content_type() != "application/x-www-form-urlencoded" {
+        return Ok(None);
+    }
+
+    let body = req.extract::<Bytes>().await?;
+    let token = url_field(&body, &config.field_name);
+    let (_, mut payload) = h1::Payload::create(true);
+    payload.unread_data(body);
+    req.set_payload(payload.into());
+    Ok(token)
+}
+
+fn url_field(body: &[u8], name: &str) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    body.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| percent_encoding::percent_decode_str(&value.replace('+', " ")).decode_utf8_lossy().into_owned())
+    })
+}
+
+/// 403 (ページはErrorHandlers経由でErrHandlerが描画する)
+fn reject<B>(req: ServiceRequest, reason: &str) -> ServiceResponse<EitherBody<B>> {
+    log::debug!("CSRF rejected: {} {} ({})", req.method(), req.path(), reason);
+    let mut response = HttpResponse::Forbidden().finish();
+    response.extensions_mut().insert(ProblemDetail(reason.to_string()));
+    response.extensions_mut().insert(ProblemSuggestions(vec![
+        "Reload the page and submit the form again.".to_string(),
+        "Make sure cookies are enabled for this site.".to_string(),
+        "Do not submit the form from another site or an old tab.".to_string(),
+    ]));
+    req.into_response(response).map_into_right_body()
+}
+
+/// テンプレートの csrf_token() の値 (with_tokenの中ならリクエストのトークン、それ以外はプレースホルダー)
+pub fn template_token() -> String {
+    RENDERING_TOKEN.with_borrow_mut(|rendering| match rendering {
+        Some((token, used)) => {
+            *used = true;
+            token.clone()
+        }
+        None => CSRF_PLACEHOLDER.to_string(),
+    })
+}
+
+/// renderの中の csrf_token() にリクエストのトークンを返させる (CSRF対策が無効なら空)
+/// 描画後に置き換えないので、クレームなどに含まれるプレースホルダーの文字列はそのまま残る
+/// トークンを使ったかも返す
+pub fn with_token<T>(req: &HttpRequest, render: impl FnOnce() -> T) -> (T, bool) {
+    let token = req.extensions().get::<CsrfToken>().map(|t| t.token.clone()).unwrap_or_default();
+    RENDERING_TOKEN.set(Some((token, false)));
+    let result = render();
+    let used = RENDERING_TOKEN.take().is_some_and(|(_, used)| used);
+    if let Some(token) = req.extensions().get::<CsrfToken>().filter(|_| used) {
+        token.used.set(true);
+    }
+    (result, used)
+}
+
+/// 描画したHTMLの csrf_token() の値をリクエストのトークンに置き換える (CSRF対策が無効なら空)
+pub fn inject_token(req: &HttpRequest, body: &str) -> Option<String> {
+    if !body.contains(CSRF_PLACEHOLDER) {
+        return None;
+    }
+    let extensions = req.extensions();
+    let token = extensions.get::<CsrfToken>();
+    if let Some(token) = token {
+        token.used.set(true);
+    }
+    Some(body.replace(CSRF_PLACEHOLDER, token.map(|t| t.token.as_str()).unwrap_or("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware, test::{call_service, init_service, TestRequest}, App};
+    use crate::handler::admin;
+    use crate::sys::init::AppConfig;
+
+    fn config(trusted_origins: &[&str]) -> CsrfConfig {
+        CsrfConfig {
+            cookie_name: "csrf_token".to_string(),
+            field_name: "csrf_token".to_string(),
+            header_name: "X-CSRF-Token".to_string(),
+            trusted_origins: trusted_origins.iter().map(|o| o.to_string()).collect(),
+            secure: true,
+        }
+    }
+
+    fn check(headers: &[(&str, &str)], trusted_origins: &[&str]) -> Result<(), &'static str> {
+        let mut req = TestRequest::post();
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+        check_origin(&req.to_http_request(), &config(trusted_origins))
+    }
+
+    #[test]
+    fn same_host_origin_or_referer() {
+        let host = ("Host", "example.com");
+        assert!(check(&[host, ("Origin", "https://example.com")], &[]).is_ok());
+        assert!(check(&[host, ("Origin", "http://EXAMPLE.com")], &[]).is_ok());
+        assert!(check(&[host, ("Referer", "https://example.com/form?x=1")], &[]).is_ok());
+        assert!(check(&[host, ("Referer", "https://example.com?x=1")], &[]).is_ok());
+        assert!(check(&[host, ("Referer", "https://example.com#top")], &[]).is_ok());
+        // Originがなければトークンだけで判定する
+        assert!(check(&[host], &[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_lookalike_hosts() {
+        let host = ("Host", "example.com");
+        for origin in [
+            "https://example.com.evil.test",
+            "https://evil.test",
+            "https://evilexample.com",
+            "https://example.com:8443",
+            "https://example.com@evil.test",
+            "example.com",
+            "null",
+            "",
+        ] {
+            assert!(check(&[host, ("Origin", origin)], &[]).is_err(), "{}", origin);
+        }
+        assert!(check(&[host, ("Referer", "https://evil.test/?https://example.com")], &[]).is_err());
+        assert!(check(&[host, ("Referer", "https://evil.test/example.com")], &[]).is_err());
+    }
+
+    #[test]
+    fn origin_takes_precedence_over_referer() {
+        let host = ("Host", "example.com");
+        assert!(check(&[host, ("Origin", "https://evil.test"), ("Referer", "https://example.com/")], &[]).is_err());
+        assert!(check(&[host, ("Origin", "null"), ("Referer", "https://example.com/")], &[]).is_err());
+    }
+
+    #[test]
+    fn port_must_match_host() {
+        let host = ("Host", "example.com:8080");
+        assert!(check(&[host, ("Origin", "http://example.com:8080")], &[]).is_ok());
+        assert!(check(&[host, ("Origin", "http://example.com")], &[]).is_err());
+    }
+
+    #[test]
+    fn missing_host_needs_trusted_origin() {
+        assert!(check(&[("Origin", "https://example.com")], &[]).is_err());
+        assert!(check(&[("Origin", "https://example.com")], &["https://example.com/"]).is_ok());
+    }
+
+    #[test]
+    fn trusted_origins_match_scheme_and_host() {
+        let host = ("Host", "api.example.com");
+        let trusted = ["https://app.example.com"];
+        assert!(check(&[host, ("Origin", "https://app.example.com")], &trusted).is_ok());
+        assert!(check(&[host, ("Origin", "https://APP.example.com")], &trusted).is_ok());
+        assert!(check(&[host, ("Referer", "https://app.example.com/page")], &trusted).is_ok());
+        assert!(check(&[host, ("Origin", "http://app.example.com")], &trusted).is_err());
+        assert!(check(&[host, ("Origin", "https://app.example.com.evil.test")], &trusted).is_err());
+        assert!(check(&[host, ("Origin", "https://app.example.com:444")], &trusted).is_err());
+    }
+
+    #[test]
+    fn url_field_decodes_value() {
+        assert_eq!(url_field(b"a=1&csrf_token=ab%2Bc+d&b=2", "csrf_token").as_deref(), Some("ab+c d"));
+        assert_eq!(url_field(b"csrf_token=first&csrf_token=second", "csrf_token").as_deref(), Some("first"));
+        assert_eq!(url_field(b"csrf_token=", "csrf_token").as_deref(), Some(""));
+        assert_eq!(url_field(b"csrf_token=a=b", "csrf_token").as_deref(), Some("a=b"));
+    }
+
+    #[test]
+    fn url_field_requires_exact_name() {
+        assert_eq!(url_field(b"csrf_token", "csrf_token"), None);
+        assert_eq!(url_field(b"xcsrf_token=a&csrf_tokenx=b", "csrf_token"), None);
+        assert_eq!(url_field(b"CSRF_TOKEN=a", "csrf_token"), None);
+        assert_eq!(url_field(b"", "csrf_token"), None);
+        assert_eq!(url_field(&[0xff, b'=', b'a'], "csrf_token"), None);
+    }
+
+    #[actix_web::test]
+    async fn admin_post_is_exempt() {
+        let app_config = AppConfig { csrf: Some(config(&[])), ..AppConfig::new() };
+        let app_set = web::Data::new(AppSet::new(app_config).await);
+        let app = init_service(
+            App::new()
+                .wrap(middleware::from_fn(middleware))
+                .app_data(app_set)
+                .service(admin::scope())
+                .route("/form", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let peer = "127.0.0.1:40000".parse().unwrap();
+        let req = TestRequest::post().uri("/_admin/render-cache/clear").peer_addr(peer).to_request();
+        assert_eq!(call_service(&app, req).await.status(), 204);
+        // 管理用以外のPOSTは検証する
+        let req = TestRequest::post().uri("/form").peer_addr(peer).to_request();
+        assert_eq!(call_service(&app, req).await.status(), 403);
+    }
+}
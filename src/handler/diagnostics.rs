@@ -0,0 +1,63 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use super::client_info::ClientInfo;
+use super::err_page::{DebugLevel, ErrPagePreview, ProblemDetail};
+use crate::sys::app_set::AppSet;
+
+/// 診断用エンドポイントを使えるか (開発モードか、diagnostics_networksからのアクセス)
+fn enabled(app_set: &AppSet, req: &HttpRequest) -> bool {
+    if app_set.app_config.dev_mode {
+        return true;
+    }
+    let ip = ClientInfo::get(req).ip;
+    app_set.app_config.diagnostics_networks.iter().any(|net| net.contains(&ip))
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    lang: Option<String>,
+    // "json" / "html" などAcceptの代わりに使う値
+    accept: Option<String>,
+    debug: Option<DebugLevel>,
+}
+
+/// err_template.html を確認するために任意の4xx/5xxのエラーページを表示する
+/// 例: /err/404?lang=ja&accept=json&debug=basic
+/// 使えない場合はサイトの通常のパスとして処理する
+#[actix_web::route("/err/{statuscode}", method = "GET", method = "HEAD")]
+pub async fn error_preview(
+    app_set: web::Data<AppSet>,
+    req: HttpRequest,
+    statuscode: web::Path<String>,
+) -> HttpResponse {
+    if !enabled(&app_set, &req) {
+        return app_set.handle_request(req).await;
+    }
+
+    let status = statuscode.parse::<u16>().ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .filter(|status| status.is_client_error() || status.is_server_error());
+    let Some(status) = status else {
+        let mut response = HttpResponse::BadRequest().finish();
+        response.extensions_mut().insert(ProblemDetail(format!("{} is not a 4xx/5xx status code", statuscode)));
+        return response;
+    };
+
+    // 使えない場合にサイトのパスとして処理できるようにエクストラクタにはしない
+    let query = match web::Query::<PreviewQuery>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(err) => {
+            let mut response = HttpResponse::BadRequest().finish();
+            response.extensions_mut().insert(ProblemDetail(err.to_string()));
+            return response;
+        }
+    };
+    let mut response = HttpResponse::build(status).finish();
+    response.extensions_mut().insert(ErrPagePreview {
+        lang: query.lang,
+        accept: query.accept,
+        debug: query.debug.unwrap_or(DebugLevel::Full),
+    });
+    response
+}
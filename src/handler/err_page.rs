@@ -1,13 +1,48 @@
-use std::collections::HashMap;
-use actix_web::{body::BoxBody, dev::ServiceResponse, HttpResponse};
-use tera::{Tera, Context};
+use actix_web::{body::BoxBody, dev::ServiceResponse, http::header, HttpResponse};
 use chrono::Utc;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tera::{Context, Tera};
+
+use super::client_info::ClientInfo;
+use super::path::normalize_path;
+use super::security_headers::csp_nonce;
+use super::suggest::PathSuggester;
+
+/// エラーレスポンスに添える説明 (エラーページとproblem+jsonの detail に出す)
+#[derive(Clone)]
+pub struct ProblemDetail(pub String);
+
+/// ステータスごとの提案の代わりに出す、原因に合わせた提案
+#[derive(Clone)]
+pub struct ProblemSuggestions(pub Vec<String>);
+
+/// エラーページのプレビュー (/err/{statuscode}) で指定した表示
+#[derive(Clone)]
+pub struct ErrPagePreview {
+    // テンプレートに lang として渡す言語
+    pub lang: Option<String>,
+    // リクエストのAcceptの代わりに使う値
+    pub accept: Option<String>,
+    pub debug: DebugLevel,
+}
+
+/// エラーページに出すデバッグ情報の量
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DebugLevel {
+    None,
+    // Host, Path, Last-Timeだけ
+    Basic,
+    Full,
+}
 
 pub struct ErrHandler {
     pub status_color: HashMap<u16, String>,
     pub status_message: HashMap<u16, String>,
     pub suggestion_fix_message: HashMap<u16, HashMap<u16, String>>,
     pub err_page_template: Tera,
+    pub path_suggester: PathSuggester,
 }
 
 impl ErrHandler {
@@ -34,6 +69,7 @@ impl ErrHandler {
         status_message.insert(416, "RequestedRangeNotSatisfiable".to_string());
         status_message.insert(417, "ExpectationFailed".to_string());
         status_message.insert(418, "ImATeapot".to_string());
+        status_message.insert(421, "MisdirectedRequest".to_string());
         status_message.insert(422, "UnprocessableEntity".to_string());
         status_message.insert(423, "Locked".to_string());
         status_message.insert(424, "FailedDependency".to_string());
@@ -96,13 +132,19 @@ impl ErrHandler {
         suggestion_fix_message.insert(406, {
             let mut map = HashMap::new();
             map.insert(1, "Check the requested media type.".to_string());
-            map.insert(2, "Ensure server supports the requested format.".to_string());
+            map.insert(
+                2,
+                "Ensure server supports the requested format.".to_string(),
+            );
             map
         });
         suggestion_fix_message.insert(407, {
             let mut map = HashMap::new();
             map.insert(1, "Verify proxy authentication.".to_string());
-            map.insert(2, "Contact network administrator for proxy details.".to_string());
+            map.insert(
+                2,
+                "Contact network administrator for proxy details.".to_string(),
+            );
             map
         });
         suggestion_fix_message.insert(408, {
@@ -121,7 +163,10 @@ impl ErrHandler {
         suggestion_fix_message.insert(410, {
             let mut map = HashMap::new();
             map.insert(1, "This resource is no longer available.".to_string());
-            map.insert(2, "Contact the website administrator for information.".to_string());
+            map.insert(
+                2,
+                "Contact the website administrator for information.".to_string(),
+            );
             map
         });
         suggestion_fix_message.insert(411, {
@@ -168,6 +213,15 @@ impl ErrHandler {
             map.insert(1, "I'm a teapot, not a coffee machine.".to_string());
             map
         });
+        suggestion_fix_message.insert(421, {
+            let mut map = HashMap::new();
+            map.insert(1, "Check the host name in the URL.".to_string());
+            map.insert(
+                2,
+                "This server is not configured for the requested host.".to_string(),
+            );
+            map
+        });
         suggestion_fix_message.insert(422, {
             let mut map = HashMap::new();
             map.insert(1, "Check request syntax and data.".to_string());
@@ -184,12 +238,15 @@ impl ErrHandler {
             map.insert(1, "Reduce header data size.".to_string());
             map
         });
-        
+
         // 5xx サーバーエラー
         suggestion_fix_message.insert(500, {
             let mut map = HashMap::new();
             map.insert(1, "Wait a few moments and retry the request".to_string());
-            map.insert(2, "Check the website's social media for updates".to_string());
+            map.insert(
+                2,
+                "Check the website's social media for updates".to_string(),
+            );
             map.insert(3, "Contact customer support".to_string());
             map
         });
@@ -224,7 +281,10 @@ impl ErrHandler {
         suggestion_fix_message.insert(505, {
             let mut map = HashMap::new();
             map.insert(1, "Verify the HTTP version used.".to_string());
-            map.insert(2, "Contact administrator to check version support.".to_string());
+            map.insert(
+                2,
+                "Contact administrator to check version support.".to_string(),
+            );
             map
         });
         suggestion_fix_message.insert(511, {
@@ -232,15 +292,13 @@ impl ErrHandler {
             map.insert(1, "Authenticate to access network.".to_string());
             map
         });
-        
-
-
 
         ErrHandler {
             status_color,
             status_message,
             suggestion_fix_message,
             err_page_template: templates,
+            path_suggester: PathSuggester::default(),
         }
     }
 
@@ -249,76 +307,184 @@ impl ErrHandler {
         let status_code = res.status().as_u16();
 
         // ステータスメッセージを取得
-        let status_message = self.status_message.get(&status_code)
+        let status_message = self
+            .status_message
+            .get(&status_code)
             .cloned()
             .unwrap_or_else(|| "Unknown Error".to_string());
 
         // ステータスコードに対応する色を取得
-        let status_color = self.status_color.get(&(status_code / 100))
+        let status_color = self
+            .status_color
+            .get(&(status_code / 100))
             .cloned()
             .unwrap_or_else(|| "#ffffff".to_string());
 
         // 提案メッセージを取得
         let suggestions = self.suggestion_fix_message.get(&status_code);
-        let suggestion_list: Vec<String> = if let Some(suggestions_map) = suggestions {
+        let tailored = res
+            .response()
+            .extensions()
+            .get::<ProblemSuggestions>()
+            .map(|s| s.0.clone());
+        let suggestion_list: Vec<String> = if let Some(tailored) = tailored {
+            tailored
+        } else if let Some(suggestions_map) = suggestions {
             suggestions_map.values().cloned().collect()
         } else {
             Vec::new()
         };
 
+        // 404の場合は存在するパスから「もしかして」候補を探す
+        let did_you_mean = if status_code == 404 {
+            let path = normalize_path(res.request().path(), false)
+                .map(|p| format!("/{}", p))
+                .unwrap_or_else(|_| res.request().path().to_string());
+            self.path_suggester.suggest(&path)
+        } else {
+            Vec::new()
+        };
+
         // デバッグ情報を作成
         let mut debug_info = HashMap::new();
-        // Host, Path, Connection, User-Agent, Last-Time, Cf-Connecting-Ip, Accept-Encoding, Accept-Languageなどのヘッダー情報を追加
-        debug_info.insert("Host".to_string(),
-            res.request().headers().get("Host")
+        // Host, Path, Connection, User-Agent, Last-Time, Client-Ip, Scheme, Accept-Encoding, Accept-Languageなどのヘッダー情報を追加
+        debug_info.insert(
+            "Host".to_string(),
+            res.request()
+                .headers()
+                .get("Host")
                 .and_then(|h| h.to_str().ok())
-                .unwrap_or("Unknown").to_string()
+                .unwrap_or("Unknown")
+                .to_string(),
         );
         debug_info.insert("Path".to_string(), res.request().path().to_string());
-        debug_info.insert("Connection".to_string(),
-            res.request().headers().get("Connection")
+        debug_info.insert(
+            "Connection".to_string(),
+            res.request()
+                .headers()
+                .get("Connection")
                 .and_then(|c| c.to_str().ok())
-                .unwrap_or("Unknown").to_string()
+                .unwrap_or("Unknown")
+                .to_string(),
         );
-        debug_info.insert("User-Agent".to_string(),
-            res.request().headers().get("User-Agent")
+        debug_info.insert(
+            "User-Agent".to_string(),
+            res.request()
+                .headers()
+                .get("User-Agent")
                 .and_then(|ua| ua.to_str().ok())
-                .unwrap_or("Unknown").to_string()
+                .unwrap_or("Unknown")
+                .to_string(),
         );
         debug_info.insert("Last-Time".to_string(), Utc::now().to_rfc3339());
-        debug_info.insert("Cf-Connecting-Ip".to_string(),
-            res.request().headers().get("Cf-Connecting-Ip")
-                .and_then(|ip| ip.to_str().ok())
-                .unwrap_or("Unknown").to_string()
-        );
-        debug_info.insert("Accept-Encoding".to_string(),
-            res.request().headers().get("Accept-Encoding")
+        // 信頼できるプロキシ経由の場合のみ転送ヘッダーを反映したクライアント情報
+        let client_info = ClientInfo::get(res.request());
+        debug_info.insert("Client-Ip".to_string(), client_info.ip.to_string());
+        debug_info.insert("Scheme".to_string(), client_info.scheme);
+        debug_info.insert(
+            "Accept-Encoding".to_string(),
+            res.request()
+                .headers()
+                .get("Accept-Encoding")
                 .and_then(|ae| ae.to_str().ok())
-                .unwrap_or("Unknown").to_string()
+                .unwrap_or("Unknown")
+                .to_string(),
         );
-        debug_info.insert("Accept-Language".to_string(),
-            res.request().headers().get("Accept-Language")
+        debug_info.insert(
+            "Accept-Language".to_string(),
+            res.request()
+                .headers()
+                .get("Accept-Language")
                 .and_then(|al| al.to_str().ok())
-                .unwrap_or("Unknown").to_string()
+                .unwrap_or("Unknown")
+                .to_string(),
         );
 
+        let detail = res
+            .response()
+            .extensions()
+            .get::<ProblemDetail>()
+            .map(|d| d.0.clone());
+        let preview = res.response().extensions().get::<ErrPagePreview>().cloned();
+
+        match preview
+            .as_ref()
+            .map(|p| p.debug)
+            .unwrap_or(DebugLevel::Full)
+        {
+            DebugLevel::None => debug_info.clear(),
+            DebugLevel::Basic => {
+                debug_info.retain(|key, _| ["Host", "Path", "Last-Time"].contains(&key.as_str()))
+            }
+            DebugLevel::Full => {}
+        }
+
+        // Allow, WWW-Authenticate, Retry-Afterなど元のレスポンスのヘッダーは引き継ぐ
+        // ボディを差し替えるので、元のボディの形式や検証子を表すヘッダーは除く
+        let replaced = [
+            header::CONTENT_TYPE,
+            header::CONTENT_LENGTH,
+            header::CONTENT_ENCODING,
+            header::ETAG,
+        ];
+        let mut response = HttpResponse::build(res.status());
+        for (name, value) in res.headers() {
+            if !replaced.contains(name) {
+                response.append_header((name.clone(), value.clone()));
+            }
+        }
+
+        // APIクライアントにはRFC 7807のproblem+jsonで返す
+        let accept = match preview.as_ref().and_then(|p| p.accept.as_deref()) {
+            Some(accept) => accept,
+            None => res
+                .request()
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or(""),
+        };
+        if wants_json(accept) {
+            let mut problem = serde_json::json!({
+                "type": "about:blank",
+                "title": res.status().canonical_reason().unwrap_or(&status_message),
+                "status": status_code,
+            });
+            if let Some(detail) = &detail {
+                problem["detail"] = detail.clone().into();
+            }
+            return response
+                .content_type("application/problem+json")
+                .body(problem.to_string());
+        }
+
         // Teraコンテキストを作成
         let mut context = Context::new();
         context.insert("code", &status_code.to_string());
         context.insert("ms", &status_message);
         context.insert("color", &status_color);
         context.insert("suggestions", &suggestion_list);
+        context.insert("did_you_mean", &did_you_mean);
+        context.insert("detail", &detail);
         context.insert("debug_info", &debug_info);
+        context.insert("csp_nonce", &csp_nonce(res.request()));
+        context.insert("client", &ClientInfo::get(res.request()));
+        context.insert("lang", &preview.and_then(|p| p.lang));
 
         // テンプレートをレンダリング
-        let rendered = self.err_page_template.render("err_template.html", &context)
+        let rendered = self
+            .err_page_template
+            .render("err_template.html", &context)
             .unwrap_or_else(|err| {
                 eprintln!("Template rendering error: {}", err);
                 "Error rendering template".to_string()
             });
 
-        HttpResponse::build(res.status())
-            .content_type("text/html")
-            .body(rendered)
+        response.content_type("text/html").body(rendered)
     }
-}
\ No newline at end of file
+}
+
+/// AcceptでHTMLよりJSONを求めているか
+fn wants_json(accept: &str) -> bool {
+    accept.contains("json") && !accept.contains("text/html")
+}
@@ -0,0 +1,55 @@
+use actix_web::http::header::{HeaderName, HeaderValue};
+use serde_json::Value;
+
+/// 先頭の "---" で囲まれたYAMLか "+++" で囲まれたTOMLのフロントマターを取り出す
+/// (フロントマター, 本文) を返す。フロントマターがなければNull
+pub fn split_front_matter(source: &str) -> Result<(Value, &str), String> {
+    for delimiter in ["---", "+++"] {
+        let Some(rest) = source.strip_prefix(delimiter) else { continue };
+        let Some(rest) = rest.strip_prefix('\n').or_else(|| rest.strip_prefix("\r\n")) else { continue };
+        let Some((matter, body)) = find_closing(rest, delimiter) else { continue };
+
+        let value: Value = if delimiter == "---" {
+            serde_yaml::from_str(matter).map_err(|e| format!("invalid YAML front matter: {}", e))?
+        } else {
+            toml::from_str(matter).map_err(|e| format!("invalid TOML front matter: {}", e))?
+        };
+        return Ok((value, body));
+    }
+    Ok((Value::Null, source))
+}
+
+/// フロントマターの headers をレスポンスヘッダーにする
+/// ヘッダーとして使えない名前や値は除き、理由を2つ目に返す
+pub fn page_headers(page: &Value) -> (Vec<(HeaderName, HeaderValue)>, Vec<String>) {
+    let mut headers = Vec::new();
+    let mut errors = Vec::new();
+    let Some(object) = page.get("headers") else {
+        return (headers, errors);
+    };
+    let Some(object) = object.as_object() else {
+        errors.push("headers must be a table".to_string());
+        return (headers, errors);
+    };
+    for (name, value) in object {
+        let header = HeaderName::from_bytes(name.as_bytes()).ok()
+            .zip(value.as_str().and_then(|v| HeaderValue::from_str(v).ok()));
+        match header {
+            Some(header) => headers.push(header),
+            None => errors.push(format!("invalid header {}: {}", name, value)),
+        }
+    }
+    (headers, errors)
+}
+
+/// 閉じ区切り行を探して (中身, 残り) を返す
+fn find_closing<'a>(rest: &'a str, delimiter: &str) -> Option<(&'a str, &'a str)> {
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == delimiter {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
@@ -0,0 +1,355 @@
+use std::fs;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, HttpResponse,
+};
+use ipnet::IpNet;
+
+use super::client_info::ClientInfo;
+use super::err_page::ProblemDetail;
+use crate::sys::app_set::AppSet;
+use crate::sys::init::{AppConfig, IpAccessList};
+use crate::sys::privilege::current_path;
+
+// リストファイルの更新日時を確認する間隔
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Allow,
+    Deny,
+}
+
+#[derive(Default)]
+struct Node {
+    children: [Option<Box<Node>>; 2],
+    access: Option<Access>,
+}
+
+/// CIDRのビット列をたどるプレフィックス木 (IPv4とIPv6で別の木)
+#[derive(Default)]
+struct PrefixTrie {
+    v4: Node,
+    v6: Node,
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, net: IpNet, access: Access) {
+        let (mut node, bits, width) = match net {
+            IpNet::V4(net) => (&mut self.v4, net.network().to_bits() as u128, 32),
+            IpNet::V6(net) => (&mut self.v6, net.network().to_bits(), 128),
+        };
+        for i in 0..net.prefix_len() as u32 {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            node = node.children[bit].get_or_insert_with(Box::default);
+        }
+        // 同じネットワークが両方にあれば拒否を優先する
+        if node.access != Some(Access::Deny) {
+            node.access = Some(access);
+        }
+    }
+
+    /// 最も長く一致したネットワークの設定
+    fn lookup(&self, ip: IpAddr) -> Option<Access> {
+        let (mut node, bits, width) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (&self.v4, ip.to_bits() as u128, 32),
+            IpAddr::V6(ip) => (&self.v6, ip.to_bits(), 128),
+        };
+        let mut found = node.access;
+        for i in 0..width {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            match &node.children[bit] {
+                Some(child) => node = child,
+                None => break,
+            }
+            found = node.access.or(found);
+        }
+        found
+    }
+}
+
+/// 許可/拒否リスト (設定とリストファイルの内容)
+struct AccessList {
+    config: IpAccessList,
+    trie: PrefixTrie,
+    has_allow: bool,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl AccessList {
+    /// 起動時の読み込み。リストファイルを使えない場合はすべて拒否する
+    fn load(config: &IpAccessList) -> AccessList {
+        AccessList::build(config).unwrap_or_else(|err| {
+            eprintln!("{} (denying all clients until it is fixed)", err);
+            // 空の木で許可リストありとして扱うとどのIPも許可されない
+            AccessList {
+                config: config.clone(),
+                trie: PrefixTrie::default(),
+                has_allow: true,
+                modified: None,
+                checked: Instant::now(),
+            }
+        })
+    }
+
+    fn build(config: &IpAccessList) -> Result<AccessList, String> {
+        let mut trie = PrefixTrie::default();
+        let mut has_allow = !config.allow.is_empty();
+        for net in &config.allow {
+            trie.insert(*net, Access::Allow);
+        }
+        for net in &config.deny {
+            trie.insert(*net, Access::Deny);
+        }
+
+        let mut modified = None;
+        if let Some(path) = &config.file {
+            modified = fs::metadata(current_path(path)).and_then(|m| m.modified()).ok();
+            let content = fs::read_to_string(current_path(path)).map_err(|e| format!("Failed to read IP list {}: {}", path, e))?;
+            let entries = parse_list(path, &content)?;
+            println!("IP list loaded: {} ({} entries)", path, entries.len());
+            for (net, access) in entries {
+                has_allow |= access == Access::Allow;
+                trie.insert(net, access);
+            }
+        }
+        Ok(AccessList { config: config.clone(), trie, has_allow, modified, checked: Instant::now() })
+    }
+
+    /// 最も長く一致したネットワークで決め、どれにも一致しなければ許可リストがある場合だけ拒否する
+    fn permits(&self, ip: IpAddr) -> bool {
+        match self.trie.lookup(ip) {
+            Some(access) => access == Access::Allow,
+            None => !self.has_allow,
+        }
+    }
+}
+
+/// 1行に "allow 203.0.113.0/24" か "deny 198.51.100.7" (# 以降はコメント)
+/// 書き込み途中のファイルを使わないように、解釈できない行があるか空ならエラーにする
+fn parse_list(path: &str, content: &str) -> Result<Vec<(IpNet, Access)>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let entry = line.split_once(char::is_whitespace).and_then(|(kind, net)| {
+            let access = match kind {
+                "allow" => Access::Allow,
+                "deny" => Access::Deny,
+                _ => return None,
+            };
+            let net = net.trim();
+            let net = net.parse::<IpNet>().ok().or_else(|| net.parse::<IpAddr>().ok().map(IpNet::from))?;
+            Some((net, access))
+        });
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => return Err(format!("IP list {}:{}: malformed line", path, i + 1)),
+        }
+    }
+    if entries.is_empty() {
+        return Err(format!("IP list {}: no entries", path));
+    }
+    Ok(entries)
+}
+
+/// 一定間隔でリストファイルの更新日時を確認し、変わっていれば読み直す
+/// 読み直せない場合は前のリストを使い続け、次の確認でまた読み直す
+fn reload_if_changed(list: &RwLock<AccessList>) {
+    if list.read().unwrap().checked.elapsed() < RELOAD_CHECK_INTERVAL {
+        return;
+    }
+    let mut list = list.write().unwrap();
+    if list.checked.elapsed() < RELOAD_CHECK_INTERVAL {
+        return;
+    }
+    list.checked = Instant::now();
+    let Some(path) = &list.config.file else {
+        return;
+    };
+    if fs::metadata(current_path(path)).and_then(|m| m.modified()).ok() != list.modified {
+        match AccessList::build(&list.config) {
+            Ok(reloaded) => *list = reloaded,
+            Err(err) => eprintln!("{} (keeping the previous list)", err),
+        }
+    }
+}
+
+struct PathRule {
+    prefix: String,
+    list: RwLock<AccessList>,
+}
+
+/// 解決済みのクライアントIPによるアクセス制限
+/// 全体のリストと、最も長く一致したパスのプレフィックスのリストの両方で許可される必要がある
+pub struct IpAccess {
+    global: RwLock<AccessList>,
+    paths: Vec<PathRule>,
+}
+
+impl IpAccess {
+    pub fn new(app_config: &AppConfig) -> Self {
+        IpAccess {
+            global: RwLock::new(AccessList::load(&app_config.ip_access)),
+            paths: app_config.ip_access_paths.iter()
+                .map(|rule| PathRule {
+                    prefix: match rule.path.trim_matches('/') {
+                        "" => String::new(),
+                        path => format!("{}/", path),
+                    },
+                    list: RwLock::new(AccessList::load(&rule.list)),
+                })
+                .collect(),
+        }
+    }
+
+    /// 設定されたリストがあるか
+    pub fn is_enabled(app_config: &AppConfig) -> bool {
+        let configured = |list: &IpAccessList| !list.allow.is_empty() || !list.deny.is_empty() || list.file.is_some();
+        configured(&app_config.ip_access) || !app_config.ip_access_paths.is_empty()
+    }
+
+    /// pathsはAppSet::request_pathsのパス (リライトされる場合はリライト前と後のどちらでも許可される必要がある)
+    fn permits(&self, ip: IpAddr, paths: &[String]) -> bool {
+        reload_if_changed(&self.global);
+        if !self.global.read().unwrap().permits(ip) {
+            return false;
+        }
+        paths.iter().all(|path| self.permits_path(ip, path))
+    }
+
+    fn permits_path(&self, ip: IpAddr, path: &str) -> bool {
+        // "admin" は "admin/" にも一致させる
+        let rule = self.paths.iter()
+            .filter(|rule| path.starts_with(&rule.prefix) || format!("{}/", path) == rule.prefix)
+            .max_by_key(|rule| rule.prefix.len());
+        match rule {
+            Some(rule) => {
+                reload_if_changed(&rule.list);
+                rule.list.read().unwrap().permits(ip)
+            }
+            None => true,
+        }
+    }
+}
+
+/// 許可されないクライアントには403を返す (ページはErrorHandlers経由でErrHandlerが描画する)
+pub async fn middleware<B: MessageBody>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let app_set = req.app_data::<web::Data<AppSet>>().cloned();
+    let Some((app_set, ip_access)) = app_set.as_ref().and_then(|a| Some((a, a.ip_access.as_ref()?))) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let ip = ClientInfo::get(req.request()).ip;
+    // 正規化できないパスは生のパスで判定する
+    let mut paths = app_set.request_paths(req.request());
+    if paths.is_empty() {
+        paths.push(req.path().trim_start_matches('/').to_string());
+    }
+    if ip_access.permits(ip, &paths) {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    }
+
+    log::debug!("IP access denied: {} {}", ip, req.path());
+    let mut response = HttpResponse::Forbidden().finish();
+    response.extensions_mut().insert(ProblemDetail("Access from your network is not allowed".to_string()));
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(entries: &[(&str, Access)]) -> PrefixTrie {
+        let mut trie = PrefixTrie::default();
+        for (net, access) in entries {
+            trie.insert(net.parse().unwrap(), *access);
+        }
+        trie
+    }
+
+    fn lookup(trie: &PrefixTrie, ip: &str) -> Option<Access> {
+        trie.lookup(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let trie = trie(&[
+            ("10.0.0.0/8", Access::Allow),
+            ("10.1.0.0/16", Access::Deny),
+            ("10.1.2.0/24", Access::Allow),
+        ]);
+        assert_eq!(lookup(&trie, "10.9.9.9"), Some(Access::Allow));
+        assert_eq!(lookup(&trie, "10.1.9.9"), Some(Access::Deny));
+        assert_eq!(lookup(&trie, "10.1.2.3"), Some(Access::Allow));
+        assert!(lookup(&trie, "11.0.0.1").is_none());
+    }
+
+    #[test]
+    fn deny_wins_for_same_network() {
+        let allow_first = trie(&[("192.0.2.0/24", Access::Allow), ("192.0.2.0/24", Access::Deny)]);
+        let deny_first = trie(&[("192.0.2.0/24", Access::Deny), ("192.0.2.0/24", Access::Allow)]);
+        assert_eq!(lookup(&allow_first, "192.0.2.1"), Some(Access::Deny));
+        assert_eq!(lookup(&deny_first, "192.0.2.1"), Some(Access::Deny));
+    }
+
+    #[test]
+    fn host_routes_and_default_route() {
+        let trie = trie(&[("0.0.0.0/0", Access::Deny), ("198.51.100.7/32", Access::Allow)]);
+        assert_eq!(lookup(&trie, "198.51.100.7"), Some(Access::Allow));
+        assert_eq!(lookup(&trie, "198.51.100.8"), Some(Access::Deny));
+        // IPv4の設定はIPv6のアドレスに一致しない
+        assert!(lookup(&trie, "2001:db8::1").is_none());
+    }
+
+    #[test]
+    fn ipv6_and_mapped_ipv4() {
+        let trie = trie(&[("2001:db8::/32", Access::Allow), ("2001:db8:bad::/48", Access::Deny), ("203.0.113.0/24", Access::Deny)]);
+        assert_eq!(lookup(&trie, "2001:db8:1::1"), Some(Access::Allow));
+        assert_eq!(lookup(&trie, "2001:db8:bad::1"), Some(Access::Deny));
+        assert!(lookup(&trie, "2001:db9::1").is_none());
+        // IPv4射影アドレスはIPv4として扱う
+        assert_eq!(lookup(&trie, "::ffff:203.0.113.9"), Some(Access::Deny));
+    }
+
+    #[test]
+    fn parse_list_rejects_partial_files() {
+        let entries = parse_list("list", "# comment\nallow 203.0.113.0/24\n\ndeny 198.51.100.7 # host\n").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1], ("198.51.100.7/32".parse().unwrap(), Access::Deny));
+        assert!(parse_list("list", "allow 203.0.113.0/24\ndeny 198.51.100.").is_err());
+        assert!(parse_list("list", "permit 203.0.113.0/24").is_err());
+        assert!(parse_list("list", "# nothing\n\n").is_err());
+    }
+
+    #[test]
+    fn allow_list_denies_unlisted() {
+        let config = IpAccessList { allow: vec!["10.0.0.0/8".parse().unwrap()], deny: vec!["10.0.0.1/32".parse().unwrap()], file: None };
+        let list = AccessList::build(&config).unwrap();
+        assert!(list.permits("10.2.3.4".parse().unwrap()));
+        assert!(!list.permits("10.0.0.1".parse().unwrap()));
+        assert!(!list.permits("192.0.2.1".parse().unwrap()));
+
+        let config = IpAccessList { deny: vec!["10.0.0.0/8".parse().unwrap()], ..IpAccessList::default() };
+        let list = AccessList::build(&config).unwrap();
+        assert!(!list.permits("10.2.3.4".parse().unwrap()));
+        assert!(list.permits("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn unreadable_file_fails_closed() {
+        let config = IpAccessList { file: Some("/nonexistent/ip-list".to_string()), ..IpAccessList::default() };
+        let list = AccessList::load(&config);
+        assert!(!list.permits("192.0.2.1".parse().unwrap()));
+        assert!(!list.permits("::1".parse().unwrap()));
+    }
+}
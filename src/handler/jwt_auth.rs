@@ -0,0 +1,303 @@
+use std::fs;
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use glob::Pattern;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+
+use super::err_page::ProblemDetail;
+use crate::sys::app_set::AppSet;
+use crate::sys::init::JwtAuthConfig;
+
+// 受け付ける署名アルゴリズム
+const ALGORITHMS: [Algorithm; 3] = [Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA];
+
+/// 検証済みのJWTのクレーム (リクエストのextensionsに入れる)
+#[derive(Clone)]
+pub struct JwtClaims(pub Value);
+
+impl JwtClaims {
+    /// ハンドラーやテンプレートから検証済みのクレームを取得する
+    pub fn get(req: &HttpRequest) -> Option<Value> {
+        req.extensions().get::<JwtClaims>().map(|c| c.0.clone())
+    }
+}
+
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+struct Route {
+    pattern: Pattern,
+    scopes: Vec<String>,
+}
+
+enum AuthError {
+    // 401 (WWW-Authenticateの値, 説明)
+    Unauthorized(String, String),
+    // 403 (WWW-Authenticateの値, 説明)
+    Forbidden(String, String),
+}
+
+/// ローカルのJWKS/PEMの鍵でBearerトークンを検証する
+pub struct JwtAuth {
+    keys: Vec<Key>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    routes: Vec<Route>,
+}
+
+impl JwtAuth {
+    pub fn new(config: &JwtAuthConfig) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        if let Some(path) = &config.jwks_path {
+            keys.extend(load_jwks(path)?);
+        }
+        if let Some(path) = &config.pem_path {
+            keys.extend(load_pem(path)?);
+        }
+        if keys.is_empty() {
+            return Err("jwt_auth: no usable keys".to_string());
+        }
+        println!("JWT keys loaded: {} keys", keys.len());
+
+        let routes = config.routes.iter()
+            .map(|route| {
+                Pattern::new(&route.path)
+                    .map(|pattern| Route { pattern, scopes: route.scopes.clone() })
+                    .map_err(|e| format!("jwt_auth: invalid route pattern {}: {}", route.path, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(JwtAuth {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway: config.leeway,
+            routes,
+        })
+    }
+
+    /// AppSet::request_pathsのパスに一致する最初のルート
+    /// リライトされる場合は、実際に配信するリライト後のパスのルートを優先する
+    fn route_for(&self, paths: &[String]) -> Option<&Route> {
+        paths.iter().rev().find_map(|path| {
+            let path = format!("/{}", path);
+            self.routes.iter().find(|route| route.pattern.matches(&path))
+        })
+    }
+
+    fn authenticate(&self, req: &ServiceRequest, route: &Route) -> Result<Value, AuthError> {
+        let invalid = |detail: &str| {
+            AuthError::Unauthorized("Bearer error=\"invalid_token\"".to_string(), detail.to_string())
+        };
+
+        let token = req.headers().get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|v| v.trim())
+            .ok_or_else(|| AuthError::Unauthorized("Bearer".to_string(), "Bearer token required".to_string()))?;
+
+        let token_header = decode_header(token).map_err(|_| invalid("Malformed token"))?;
+        if !ALGORITHMS.contains(&token_header.alg) {
+            return Err(invalid("Unsupported algorithm"));
+        }
+
+        let mut validation = Validation::new(token_header.alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        match &self.issuer {
+            Some(issuer) => validation.set_issuer(&[issuer]),
+            None => validation.iss = None,
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        // kidがあればその鍵、なければアルゴリズムが一致する鍵を順に試す
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithm == token_header.alg
+                && (token_header.kid.is_none() || key.kid == token_header.kid)
+        });
+        let mut last_error = None;
+        let mut claims = None;
+        for key in candidates {
+            match decode::<Value>(token, &key.key, &validation) {
+                Ok(data) => {
+                    claims = Some(data.claims);
+                    break;
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+        let claims = claims.ok_or_else(|| match last_error {
+            Some(err) => invalid(&format!("Token rejected: {}", err)),
+            None => invalid("No matching key"),
+        })?;
+
+        let granted = scopes(&claims);
+        let missing: Vec<&String> = route.scopes.iter().filter(|s| !granted.contains(s)).collect();
+        if !missing.is_empty() {
+            let required = route.scopes.join(" ");
+            return Err(AuthError::Forbidden(
+                format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", required),
+                format!("Required scope: {}", required),
+            ));
+        }
+        Ok(claims)
+    }
+}
+
+/// "scope" (スペース区切り) か "scp" (配列) のスコープ
+fn scopes(claims: &Value) -> Vec<String> {
+    match (claims.get("scope"), claims.get("scp")) {
+        (Some(Value::String(scope)), _) => scope.split_whitespace().map(|s| s.to_string()).collect(),
+        (_, Some(Value::Array(scp))) => scp.iter().filter_map(|s| s.as_str().map(|s| s.to_string())).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn load_jwks(path: &str) -> Result<Vec<Key>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("failed to read JWKS {}: {}", path, e))?;
+    let jwks: JwkSet = serde_json::from_str(&content).map_err(|e| format!("failed to parse JWKS {}: {}", path, e))?;
+
+    let mut keys = Vec::new();
+    for jwk in &jwks.keys {
+        let kid = jwk.common.key_id.clone();
+        let Ok(key) = DecodingKey::from_jwk(jwk) else {
+            eprintln!("JWKS {}: skipped unsupported key {:?}", path, kid);
+            continue;
+        };
+        // algがなければ鍵の種類から決める
+        let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+            (Some(alg), _) => alg.to_string().parse().ok(),
+            (None, jsonwebtoken::jwk::AlgorithmParameters::RSA(_)) => Some(Algorithm::RS256),
+            (None, jsonwebtoken::jwk::AlgorithmParameters::EllipticCurve(_)) => Some(Algorithm::ES256),
+            (None, jsonwebtoken::jwk::AlgorithmParameters::OctetKeyPair(_)) => Some(Algorithm::EdDSA),
+            _ => None,
+        };
+        match algorithm.filter(|alg| ALGORITHMS.contains(alg)) {
+            Some(algorithm) => keys.push(Key { kid, algorithm, key }),
+            None => eprintln!("JWKS {}: skipped key {:?} with unsupported algorithm", path, kid),
+        }
+    }
+    Ok(keys)
+}
+
+/// PEMは種類が分からないので、読み込めたアルゴリズムすべての鍵にする
+fn load_pem(path: &str) -> Result<Vec<Key>, String> {
+    let pem = fs::read(path).map_err(|e| format!("failed to read PEM {}: {}", path, e))?;
+    let keys: Vec<Key> = [
+        (Algorithm::RS256, DecodingKey::from_rsa_pem(&pem)),
+        (Algorithm::ES256, DecodingKey::from_ec_pem(&pem)),
+        (Algorithm::EdDSA, DecodingKey::from_ed_pem(&pem)),
+    ]
+    .into_iter()
+    .filter_map(|(algorithm, key)| key.ok().map(|key| Key { kid: None, algorithm, key }))
+    .collect();
+    if keys.is_empty() {
+        return Err(format!("PEM {} is not an RSA, EC or Ed25519 public key", path));
+    }
+    Ok(keys)
+}
+
+/// ルートに一致するリクエストのBearerトークンを検証し、クレームをextensionsに入れる
+/// 失敗した場合は401/403を返す (ページはErrorHandlers経由でErrHandlerが描画する)
+pub async fn middleware<B: MessageBody>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let app_set = req.app_data::<web::Data<AppSet>>().cloned();
+    let Some((app_set, jwt_auth)) = app_set.as_ref().and_then(|a| Some((a, a.jwt_auth.as_ref()?))) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(route) = jwt_auth.route_for(&app_set.request_paths(req.request())) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    match jwt_auth.authenticate(&req, route) {
+        Ok(claims) => {
+            req.extensions_mut().insert(JwtClaims(claims));
+            next.call(req).await.map(|res| res.map_into_left_body())
+        }
+        Err(err) => {
+            let (mut response, challenge, detail) = match err {
+                AuthError::Unauthorized(challenge, detail) => (HttpResponse::Unauthorized(), challenge, detail),
+                AuthError::Forbidden(challenge, detail) => (HttpResponse::Forbidden(), challenge, detail),
+            };
+            log::debug!("JWT auth failed: {} ({})", req.path(), detail);
+            let mut response = response.insert_header((header::WWW_AUTHENTICATE, challenge)).finish();
+            response.extensions_mut().insert(ProblemDetail(detail));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn jwt_auth(patterns: &[&str]) -> JwtAuth {
+        JwtAuth {
+            keys: Vec::new(),
+            issuer: None,
+            audience: None,
+            leeway: 0,
+            routes: patterns.iter()
+                .map(|p| Route { pattern: Pattern::new(p).unwrap(), scopes: vec![p.to_string()] })
+                .collect(),
+        }
+    }
+
+    fn route<'a>(jwt_auth: &'a JwtAuth, paths: &[&str]) -> Option<&'a str> {
+        let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+        jwt_auth.route_for(&paths).map(|route| route.scopes[0].as_str())
+    }
+
+    #[test]
+    fn first_matching_route() {
+        let jwt_auth = jwt_auth(&["/api/admin/*", "/api/*"]);
+        assert_eq!(route(&jwt_auth, &["api/admin/users"]), Some("/api/admin/*"));
+        assert_eq!(route(&jwt_auth, &["api/items/1"]), Some("/api/*"));
+        assert_eq!(route(&jwt_auth, &["index.html"]), None);
+        // "/api/*" は "/api" 自身には一致しない
+        assert_eq!(route(&jwt_auth, &["api"]), None);
+    }
+
+    #[test]
+    fn rewritten_path_takes_precedence() {
+        let jwt_auth = jwt_auth(&["/internal/*", "/public/*"]);
+        // /public/x が /internal/x にリライトされる
+        assert_eq!(route(&jwt_auth, &["public/x", "internal/x"]), Some("/internal/*"));
+        // リライト先が保護されていなければリクエストのパスのルート
+        assert_eq!(route(&jwt_auth, &["public/x", "assets/x"]), Some("/public/*"));
+        assert_eq!(route(&jwt_auth, &["assets/x", "internal/x"]), Some("/internal/*"));
+        assert_eq!(route(&jwt_auth, &["assets/x", "other/x"]), None);
+    }
+
+    #[test]
+    fn matches_normalized_paths_only() {
+        let jwt_auth = jwt_auth(&["/api/*"]);
+        // 正規化できないパスはrequest_pathsが空にするので、どのルートにも一致しない
+        assert_eq!(route(&jwt_auth, &[]), None);
+        // "/public/../api/x" は正規化で "api/x" になる
+        let path = crate::handler::path::normalize_path("/public/../api/x", false).unwrap();
+        assert_eq!(route(&jwt_auth, &[path.as_str()]), Some("/api/*"));
+    }
+
+    #[test]
+    fn scopes_from_scope_or_scp() {
+        assert_eq!(scopes(&json!({"scope": "read  write"})), vec!["read", "write"]);
+        assert_eq!(scopes(&json!({"scp": ["read", 1, "write"]})), vec!["read", "write"]);
+        assert_eq!(scopes(&json!({"scope": ["read"]})), Vec::<String>::new());
+        assert_eq!(scopes(&json!({})), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// 上限を超えたら最も長く使われていないものから捨てるHashMap
+/// 使った順の記録は古いものを後でまとめて捨てるので、どの操作も償却O(1)
+pub struct LruMap<K, V> {
+    capacity: usize,
+    // キー -> (値, 最後に使った番号)
+    entries: HashMap<K, (V, u64)>,
+    // (キー, 使った番号) の古い順。番号がentriesと違うものは使われなくなった記録
+    order: VecDeque<(K, u64)>,
+    counter: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruMap { capacity, entries: HashMap::new(), order: VecDeque::new(), counter: 0 }
+    }
+
+    /// keyの値を使う (なければinsertで作る)
+    pub fn get_or_insert_with(&mut self, key: K, insert: impl FnOnce() -> V) -> &mut V {
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        self.counter += 1;
+        let stamp = self.counter;
+        self.entries.entry(key.clone()).or_insert_with(|| (insert(), stamp)).1 = stamp;
+        self.order.push_back((key.clone(), stamp));
+        // 記録が増えすぎたら使われなくなったものを捨てる
+        if self.order.len() > self.capacity.max(1) * 2 {
+            let entries = &self.entries;
+            self.order.retain(|(key, stamp)| entries.get(key).is_some_and(|(_, s)| s == stamp));
+        }
+        &mut self.entries.get_mut(&key).unwrap().0
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|(value, _)| value)
+    }
+
+    /// 最も長く使われていないものを捨てる
+    fn evict(&mut self) {
+        while let Some((key, stamp)) = self.order.pop_front() {
+            if self.entries.get(&key).is_some_and(|(_, s)| *s == stamp) {
+                self.entries.remove(&key);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+use serde::Serialize;
+use serde_json::Value;
+use tera::{Context, Tera};
+
+use super::front_matter::split_front_matter;
+use super::client_info::ClientInfo;
+use super::security_headers::NONCE_PLACEHOLDER;
+
+#[derive(Serialize)]
+struct TocEntry {
+    level: u8,
+    id: String,
+    text: String,
+}
+
+/// Markdownをレイアウトテンプレートに埋め込んだHTMLにする
+/// フロントマターは page として、title と description はトップレベルにも渡す
+/// _data/ の内容は data として渡す
+pub fn render_markdown(source: &str, tera: &Tera, layout: &str, data: &Value) -> Result<String, String> {
+    let (front_matter, body) = split_front_matter(source)?;
+
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+    let mut events: Vec<Event> = Parser::new_ext(body, options).collect();
+
+    // 見出しにアンカーを付けて目次を作る
+    let mut toc = Vec::new();
+    let mut used_ids: HashMap<String, usize> = HashMap::new();
+    for i in 0..events.len() {
+        let Event::Start(Tag::Heading { level, id, .. }) = &events[i] else { continue };
+        let level = *level as u8;
+        let text: String = events[i + 1..].iter()
+            .take_while(|e| !matches!(e, Event::End(TagEnd::Heading(_))))
+            .filter_map(|e| match e {
+                Event::Text(t) | Event::Code(t) => Some(t.as_ref()),
+                _ => None,
+            })
+            .collect();
+        let anchor = match id {
+            Some(id) => id.to_string(),
+            None => {
+                let slug = slugify(&text);
+                let count = used_ids.entry(slug.clone()).or_insert(0);
+                *count += 1;
+                if *count == 1 { slug } else { format!("{}-{}", slug, *count - 1) }
+            }
+        };
+        if let Event::Start(Tag::Heading { id, .. }) = &mut events[i] {
+            *id = Some(anchor.clone().into());
+        }
+        toc.push(TocEntry { level, id: anchor, text });
+    }
+
+    let mut content = String::new();
+    html::push_html(&mut content, events.into_iter());
+
+    let mut context = Context::new();
+    for key in ["title", "description"] {
+        if let Some(value) = front_matter.get(key) {
+            context.insert(key, value);
+        }
+    }
+    if !context.contains_key("title") {
+        if let Some(first) = toc.first() {
+            context.insert("title", &first.text);
+        }
+    }
+    context.insert("page", &front_matter);
+    context.insert("content", &content);
+    context.insert("toc", &toc);
+    context.insert("data", data);
+    // レスポンス時にリクエストごとのnonceへ置き換える
+    context.insert("csp_nonce", NONCE_PLACEHOLDER);
+    context.insert("client", &ClientInfo::placeholder_context());
+
+    tera.render(layout, &context).map_err(|e| format!("failed to render markdown layout: {:?}", e))
+}
+
+/// 見出しテキストからアンカー用のIDを作る
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().chars().flat_map(|c| c.to_lowercase()) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches('-').to_string();
+    if slug.is_empty() { "section".to_string() } else { slug }
+}
+
@@ -1,2 +1,24 @@
 pub mod err_page;
-pub mod router;
\ No newline at end of file
+pub mod router;
+pub mod client_info;
+pub mod path;
+pub mod lru;
+pub mod rewrite;
+pub mod autoindex;
+pub mod suggest;
+pub mod not_found_log;
+pub mod admin;
+pub mod front_matter;
+pub mod markdown;
+pub mod template_helpers;
+pub mod render_cache;
+pub mod security_headers;
+pub mod rate_limit;
+pub mod basic_auth;
+pub mod jwt_auth;
+pub mod session;
+pub mod csrf;
+pub mod cors;
+pub mod block_rules;
+pub mod ip_access;
+pub mod diagnostics;
\ No newline at end of file
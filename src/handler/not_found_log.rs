@@ -0,0 +1,152 @@
+use std::fs;
+use std::sync::Mutex;
+
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::lru::LruMap;
+use crate::sys::app_set::request_host;
+use crate::sys::privilege::current_path;
+
+// 保存するパスとリファラーの最大長 (長いものは切り詰める)
+const MAX_FIELD_LENGTH: usize = 512;
+
+#[derive(Clone, Serialize)]
+pub struct NotFoundEntry {
+    pub path: String,
+    pub referrer: Option<String>,
+    // リファラーが自サイトのページ (サイト内のリンク切れ)
+    pub internal: bool,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// 404になったパスをパスとリファラーごとに集計する (上限を超えたら最も長く発生していないものから捨てる)
+pub struct NotFoundLog {
+    max_entries: usize,
+    entries: Mutex<LruMap<(String, Option<String>), NotFoundEntry>>,
+}
+
+impl NotFoundLog {
+    pub fn new(max_entries: usize) -> Self {
+        NotFoundLog {
+            max_entries,
+            entries: Mutex::new(LruMap::new(max_entries)),
+        }
+    }
+
+    pub fn record(&self, req: &HttpRequest) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let path = truncate(req.path());
+        let referrer = req.headers().get("Referer")
+            .and_then(|r| r.to_str().ok())
+            .map(truncate);
+        let internal = referrer.as_deref()
+            .and_then(referrer_host)
+            .is_some_and(|host| host == request_host(req));
+        let now = Utc::now();
+
+        let mut entries = self.entries.lock().unwrap();
+        let key = (path, referrer);
+        let entry = entries.get_or_insert_with(key.clone(), || NotFoundEntry {
+            path: key.0,
+            referrer: key.1,
+            internal,
+            count: 0,
+            first_seen: now,
+            last_seen: now,
+        });
+        entry.count += 1;
+        entry.last_seen = now;
+    }
+
+    /// 件数の多い順
+    pub fn snapshot(&self) -> Vec<NotFoundEntry> {
+        let mut entries: Vec<NotFoundEntry> = self.entries.lock().unwrap().values().cloned().collect();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+        entries
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("path,referrer,internal,count,first_seen,last_seen\n");
+        for entry in self.snapshot() {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                csv_field(&entry.path),
+                csv_field(entry.referrer.as_deref().unwrap_or("")),
+                entry.internal,
+                entry.count,
+                entry.first_seen.to_rfc3339(),
+                entry.last_seen.to_rfc3339(),
+            ));
+        }
+        csv
+    }
+
+    pub fn dump(&self, path: &str) -> std::io::Result<()> {
+        fs::write(current_path(path), self.to_csv())
+    }
+}
+
+fn referrer_host(referrer: &str) -> Option<String> {
+    let rest = referrer.split_once("://")?.1;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit('@').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// 文字の境界でMAX_FIELD_LENGTHバイト以下に切り詰める
+fn truncate(value: &str) -> String {
+    let mut end = value.len().min(MAX_FIELD_LENGTH);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value[..end].to_string()
+}
+
+/// 表計算ソフトで数式として実行されないよう、先頭が = + - @ などの値には ' を付ける
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn csv_fields_are_quoted_and_formulas_escaped() {
+        assert_eq!(csv_field("/a"), "/a");
+        assert_eq!(csv_field("/a,b"), "\"/a,b\"");
+        assert_eq!(csv_field("say \"hi\"\n"), "\"say \"\"hi\"\"\n\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+    }
+
+    #[test]
+    fn referrer_formula_is_escaped_in_csv() {
+        let log = NotFoundLog::new(10);
+        log.record(&TestRequest::with_uri("/missing").insert_header(("Referer", "=cmd|' /C calc'!A0")).to_http_request());
+        let csv = log.to_csv();
+        assert!(csv.contains("/missing,'=cmd|' /C calc'!A0,false,1,"), "{}", csv);
+    }
+}
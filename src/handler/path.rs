@@ -0,0 +1,130 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use unicode_normalization::UnicodeNormalization;
+
+// パスセグメント内でエンコードが必要な文字 ("/" はそのまま残す)
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>')
+    .add(b'?').add(b'`').add(b'{').add(b'}').add(b'\\');
+
+#[derive(Debug)]
+pub enum PathError {
+    // %xx の後が不正なUTF-8
+    InvalidUtf8,
+    // %2F (エンコードされたスラッシュ) や %00 (NUL) を含む
+    ForbiddenChar,
+    // .. でルートより上に出ようとした
+    Traversal,
+}
+
+/// リクエストパスをstatic_cacheのキーと同じ形に正規化する
+/// 先頭の "/" は取り除き、ディレクトリを指す場合は末尾に "/" を残す ("/" は "" になる)
+pub fn normalize_path(raw: &str, nfc: bool) -> Result<String, PathError> {
+    let mut segments: Vec<String> = Vec::new();
+    let mut is_dir = false;
+
+    for segment in raw.split('/') {
+        let decoded = percent_decode_str(segment)
+            .decode_utf8()
+            .map_err(|_| PathError::InvalidUtf8)?;
+        if decoded.contains(['/', '\\', '\0']) {
+            return Err(PathError::ForbiddenChar);
+        }
+
+        is_dir = true;
+        match decoded.as_ref() {
+            "" | "." => {}
+            ".." => {
+                segments.pop().ok_or(PathError::Traversal)?;
+            }
+            _ => {
+                segments.push(decoded.into_owned());
+                is_dir = false;
+            }
+        }
+    }
+
+    let mut path = segments.join("/");
+    if is_dir && !path.is_empty() {
+        path.push('/');
+    }
+    if nfc {
+        path = normalize_key(&path);
+    }
+    Ok(path)
+}
+
+/// キャッシュのキーに使うUnicode NFC正規化
+pub fn normalize_key(key: &str) -> String {
+    key.nfc().collect()
+}
+
+/// 正規化済みのパスからLocationヘッダーなどに使うURLパスを作る
+pub fn encode_path(path: &str) -> String {
+    format!("/{}", utf8_percent_encode(path, PATH_SEGMENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(raw: &str) -> Result<String, PathError> {
+        normalize_path(raw, false)
+    }
+
+    #[test]
+    fn keeps_directory_slash() {
+        assert_eq!(normalize("/").unwrap(), "");
+        assert_eq!(normalize("").unwrap(), "");
+        assert_eq!(normalize("/docs").unwrap(), "docs");
+        assert_eq!(normalize("/docs/").unwrap(), "docs/");
+        assert_eq!(normalize("/docs/.").unwrap(), "docs/");
+        assert_eq!(normalize("//docs//a.html").unwrap(), "docs/a.html");
+    }
+
+    #[test]
+    fn resolves_dot_segments() {
+        assert_eq!(normalize("/a/./b/../c.html").unwrap(), "a/c.html");
+        assert_eq!(normalize("/a/b/..").unwrap(), "a/");
+        assert_eq!(normalize("/a/..").unwrap(), "");
+        assert_eq!(normalize("/a/%2e%2e/b").unwrap(), "b");
+        assert_eq!(normalize("/a/%2E/b").unwrap(), "a/b");
+        // "..." は普通の名前
+        assert_eq!(normalize("/.../a").unwrap(), ".../a");
+    }
+
+    #[test]
+    fn rejects_traversal_above_root() {
+        for raw in ["/..", "/../etc/passwd", "/a/../../etc/passwd", "/%2e%2e/etc/passwd", "/.%2E/x", "/a/./../.."] {
+            assert!(matches!(normalize(raw), Err(PathError::Traversal)), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn rejects_encoded_separators_and_nul() {
+        for raw in ["/a%2Fb", "/..%2Fetc/passwd", "/..%2f..%2fetc", "/a%5Cb", "/..%5C..%5Cwindows", "/a%00.html"] {
+            assert!(matches!(normalize(raw), Err(PathError::ForbiddenChar)), "{}", raw);
+        }
+        assert!(matches!(normalize("/a\\b"), Err(PathError::ForbiddenChar)));
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert!(matches!(normalize("/%ff"), Err(PathError::InvalidUtf8)));
+        assert!(matches!(normalize("/%c0%ae%c0%ae/etc"), Err(PathError::InvalidUtf8)));
+    }
+
+    #[test]
+    fn decodes_and_normalizes_unicode() {
+        assert_eq!(normalize("/caf%C3%A9.html").unwrap(), "café.html");
+        // e + 結合アクセント
+        assert_eq!(normalize("/cafe%CC%81.html").unwrap(), "cafe\u{301}.html");
+        assert_eq!(normalize_path("/cafe%CC%81.html", true).unwrap(), "café.html");
+    }
+
+    #[test]
+    fn encode_path_round_trips() {
+        let path = "a b/100%/#x?.html";
+        assert_eq!(encode_path(path), "/a%20b/100%25/%23x%3F.html");
+        assert_eq!(normalize(&encode_path(path)).unwrap(), path);
+    }
+}
@@ -0,0 +1,241 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web, HttpResponse,
+};
+use glob::Pattern;
+
+use super::client_info::ClientInfo;
+use super::lru::LruMap;
+use crate::sys::app_set::AppSet;
+use crate::sys::init::RateLimitConfig;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Limit {
+    burst: f64,
+    per_second: f64,
+}
+
+impl Limit {
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+    }
+
+    /// 満タンに戻るまでの秒数
+    fn seconds_until_full(&self, tokens: f64) -> u64 {
+        ((self.burst - tokens) / self.per_second).ceil() as u64
+    }
+}
+
+struct Group {
+    // バケットのキー ("" はグループに属さないパス)
+    name: String,
+    pattern: Option<Pattern>,
+    limit: Limit,
+}
+
+/// 判定結果 (RateLimit-* ヘッダーの値)
+struct Decision {
+    allowed: bool,
+    group: usize,
+    limit: u64,
+    remaining: u64,
+    reset: u64,
+    retry_after: u64,
+}
+
+/// 解決済みのクライアントIP (とパスのグループ) ごとのトークンバケット
+/// IPv6は/64ごとにまとめる (1台に割り当てられるアドレスを変えて回避させない)
+/// バケット数はmax_clientsまでで、超えたら最も長く使われていないものから捨てる
+pub struct RateLimiter {
+    groups: Vec<Group>,
+    buckets: Mutex<LruMap<(IpAddr, usize), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let mut groups: Vec<Group> = config.groups.iter()
+            .map(|group| Group {
+                name: group.name.clone(),
+                pattern: Some(Pattern::new(&group.path).expect("Invalid rate limit group pattern")),
+                limit: Limit { burst: group.burst as f64, per_second: group.per_second },
+            })
+            .collect();
+        // どのグループにも一致しないパス
+        groups.push(Group {
+            name: String::new(),
+            pattern: None,
+            limit: Limit { burst: config.burst as f64, per_second: config.per_second },
+        });
+        RateLimiter {
+            groups,
+            buckets: Mutex::new(LruMap::new(config.max_clients)),
+        }
+    }
+
+    /// 1回分消費せずに、制限中なら再試行までの秒数を返す
+    pub fn retry_after(&self, ip: IpAddr, path: &str) -> Option<u64> {
+        let decision = self.check(ip, self.group_for(&[path]), false);
+        (!decision.allowed).then_some(decision.retry_after)
+    }
+
+    /// 1回分消費する。制限を超えていればfalse
+    pub fn consume(&self, ip: IpAddr, path: &str) -> bool {
+        self.check(ip, self.group_for(&[path]), true).allowed
+    }
+
+    /// いずれかのパスに一致する最初のグループ (リライトされる場合はリライト前と後のパス)
+    fn group_for<S: AsRef<str>>(&self, paths: &[S]) -> usize {
+        self.groups.iter()
+            .position(|g| g.pattern.as_ref().is_none_or(|p| paths.iter().any(|path| p.matches(path.as_ref()))))
+            .unwrap_or(self.groups.len() - 1)
+    }
+
+    fn check(&self, ip: IpAddr, index: usize, consume: bool) -> Decision {
+        let limit = &self.groups[index].limit;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_with((client_key(ip), index), || Bucket { tokens: limit.burst, updated: now });
+        limit.refill(bucket, now);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed && consume {
+            bucket.tokens -= 1.0;
+        }
+        Decision {
+            allowed,
+            group: index,
+            limit: limit.burst as u64,
+            remaining: bucket.tokens.floor() as u64,
+            reset: limit.seconds_until_full(bucket.tokens),
+            retry_after: ((1.0 - bucket.tokens) / limit.per_second).ceil().max(1.0) as u64,
+        }
+    }
+}
+
+/// バケットのキーにするアドレス (IPv6は/64のネットワーク)
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & (u128::MAX << 64))),
+        ip => ip,
+    }
+}
+
+/// 制限を超えたリクエストには429を返す (ページはErrorHandlers経由でErrHandlerが描画する)
+pub async fn middleware<B: MessageBody>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let app_set = req.app_data::<web::Data<AppSet>>().cloned();
+    let Some((app_set, limiter)) = app_set.as_ref().and_then(|a| Some((a, a.rate_limiter.as_ref()?))) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let ip = ClientInfo::get(req.request()).ip;
+    // グループは正規化したパスで決める (正規化できないパスは生のパス)
+    let mut paths: Vec<String> = app_set.request_paths(req.request()).iter().map(|path| format!("/{}", path)).collect();
+    if paths.is_empty() {
+        paths.push(req.path().to_string());
+    }
+    let decision = limiter.check(ip, limiter.group_for(&paths), true);
+    let headers = [
+        ("ratelimit-limit", decision.limit),
+        ("ratelimit-remaining", decision.remaining),
+        ("ratelimit-reset", decision.reset),
+    ];
+
+    if !decision.allowed {
+        log::debug!("Rate limited: {} {} (group: {:?})", ip, req.path(), limiter.groups[decision.group].name);
+        let mut response = HttpResponse::TooManyRequests();
+        response.insert_header(("Retry-After", decision.retry_after));
+        for (name, value) in headers {
+            response.insert_header((name, value));
+        }
+        return Ok(req.into_response(response.finish()).map_into_right_body());
+    }
+
+    let mut res = next.call(req).await?;
+    for (name, value) in headers {
+        res.headers_mut().insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test::{call_service, init_service, TestRequest}, App};
+    use crate::sys::init::{AppConfig, RateLimitGroup};
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            burst: 100,
+            per_second: 1.0,
+            groups: vec![RateLimitGroup { name: "api".to_string(), path: "/api/*".to_string(), burst: 1, per_second: 0.001 }],
+            max_clients: 100,
+        }
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn bucket_per_client_and_group() {
+        let limiter = RateLimiter::new(&config());
+        assert!(limiter.consume(ip("192.0.2.1"), "/api/a"));
+        assert!(!limiter.consume(ip("192.0.2.1"), "/api/b"));
+        assert!(limiter.retry_after(ip("192.0.2.1"), "/api/a").is_some());
+        assert!(limiter.consume(ip("192.0.2.1"), "/index.html"));
+        assert!(limiter.consume(ip("192.0.2.2"), "/api/a"));
+    }
+
+    #[test]
+    fn ipv6_shares_bucket_per_64() {
+        let limiter = RateLimiter::new(&config());
+        assert!(limiter.consume(ip("2001:db8:1:2::1"), "/api/a"));
+        assert!(!limiter.consume(ip("2001:db8:1:2:ffff::9"), "/api/a"));
+        assert!(limiter.consume(ip("2001:db8:1:3::1"), "/api/a"));
+        assert_eq!(client_key(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn group_for_any_path() {
+        let limiter = RateLimiter::new(&config());
+        assert_eq!(limiter.group_for(&["/api/x"]), 0);
+        assert_eq!(limiter.group_for(&["/page", "/api/x"]), 0);
+        assert_eq!(limiter.group_for(&["/page"]), 1);
+        assert_eq!(limiter.group_for::<&str>(&[]), 1);
+    }
+
+    #[actix_web::test]
+    async fn groups_match_normalized_path() {
+        let app_config = AppConfig { rate_limit: Some(config()), ..AppConfig::new() };
+        let app_set = web::Data::new(AppSet::new(app_config).await);
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(middleware))
+                .app_data(app_set)
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (i, path) in ["/api/x", "//api/x", "/%61pi/x", "/./api/x", "/static/../api/x"].iter().enumerate() {
+            let peer = format!("192.0.2.{}:40000", i + 1).parse().unwrap();
+            let first = call_service(&app, TestRequest::get().uri("/api/x").peer_addr(peer).to_request()).await;
+            assert_eq!(first.status(), 200);
+            let second = call_service(&app, TestRequest::get().uri(path).peer_addr(peer).to_request()).await;
+            assert_eq!(second.status(), 429, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use glob::Pattern;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone)]
+pub struct CachedPage {
+    pub body: Bytes,
+    pub etag: String,
+    created: Instant,
+}
+
+/// リクエストに依存しないテンプレートの描画結果のキャッシュ
+/// フロントマターの cache = true か、設定のglobパターンに一致したものだけを対象にする
+pub struct RenderCache {
+    ttl: Duration,
+    patterns: Vec<Pattern>,
+    entries: RwLock<HashMap<String, CachedPage>>,
+}
+
+impl RenderCache {
+    pub fn new(patterns: &[String], ttl_secs: u64) -> Self {
+        RenderCache {
+            ttl: Duration::from_secs(ttl_secs),
+            patterns: patterns.iter()
+                .map(|p| Pattern::new(p).expect("Invalid render cache pattern"))
+                .collect(),
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// フロントマターの cache が設定の指定より優先される
+    pub fn is_cacheable(&self, key: &str, page: &Value) -> bool {
+        match page.get("cache").and_then(|c| c.as_bool()) {
+            Some(cache) => cache,
+            None => self.patterns.iter().any(|p| p.matches(key)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedPage> {
+        let entries = self.entries.read().unwrap();
+        entries.get(key)
+            .filter(|page| page.created.elapsed() < self.ttl)
+            .cloned()
+    }
+
+    pub fn insert(&self, key: &str, body: Bytes) -> CachedPage {
+        let page = CachedPage {
+            etag: etag_for(&body),
+            body,
+            created: Instant::now(),
+        };
+        self.entries.write().unwrap().insert(key.to_string(), page.clone());
+        page
+    }
+
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
+
+/// 内容から作る強いETag
+pub fn etag_for(content: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(&Sha256::digest(content)[..8]))
+}
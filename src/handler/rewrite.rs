@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::fs;
+
+use actix_web::HttpRequest;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use regex::Regex;
+use serde::Deserialize;
+
+use super::path::{encode_path, normalize_path};
+use crate::sys::app_set::request_host;
+
+// 内部リライトを連続して適用する最大回数
+const MAX_REWRITES: usize = 10;
+// 置換先のクエリでエンコードが必要な文字
+const QUERY: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'<').add(b'>');
+
+/// ルールファイル (TOML) の1エントリ
+///
+/// ```toml
+/// [[rule]]
+/// from = "/blog/*/*"
+/// kind = "glob"        # exact (デフォルト) / regex / glob
+/// to = "/posts/$1/$2"
+/// status = 308         # 301 / 302 / 307 / 308。rewrite = true なら省略
+/// host = "old.example.com"
+/// query = "^lang=ja"   # クエリ文字列に対する正規表現
+/// ```
+#[derive(Deserialize)]
+struct RuleEntry {
+    from: String,
+    #[serde(default)]
+    kind: RuleKind,
+    to: String,
+    #[serde(default)]
+    status: Option<u16>,
+    #[serde(default)]
+    rewrite: bool,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    query: Option<String>,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RuleKind {
+    #[default]
+    Exact,
+    Regex,
+    Glob,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default)]
+    rule: Vec<RuleEntry>,
+}
+
+struct Rule {
+    // exactの場合はNone
+    pattern: Option<Regex>,
+    to: String,
+    // Noneなら内部リライト
+    status: Option<u16>,
+    host: Option<String>,
+    query: Option<Regex>,
+}
+
+pub enum RewriteResult {
+    // (ステータス, Location)
+    Redirect(u16, String),
+    // 別のパスとして処理を続ける
+    Rewrite(String),
+    // MAX_REWRITES回リライトしても止まらない (最後のパス)
+    TooManyRewrites(String),
+    None,
+}
+
+/// リダイレクト/リライトルール
+/// exactのルールはHashMapで引き、regex/globのルールはファイル順に評価する
+#[derive(Default)]
+pub struct RewriteRules {
+    exact: HashMap<String, Vec<usize>>,
+    patterns: Vec<usize>,
+    rules: Vec<Rule>,
+}
+
+impl RewriteRules {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read rewrite rules {}: {}", path, e))?;
+        let rules = RewriteRules::parse(&content).map_err(|e| format!("rewrite rules {}: {}", path, e))?;
+        println!("Rewrite rules loaded: {} rules", rules.rules.len());
+        Ok(rules)
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let file: RuleFile = toml::from_str(content).map_err(|e| e.to_string())?;
+
+        let mut rules = RewriteRules::default();
+        for (i, entry) in file.rule.into_iter().enumerate() {
+            let status = match (entry.rewrite, entry.status) {
+                (true, _) => None,
+                (false, Some(code @ (301 | 302 | 307 | 308))) => Some(code),
+                (false, None) => Some(301),
+                (false, Some(code)) => return Err(format!("rule {}: unsupported redirect status {}", i + 1, code)),
+            };
+            let pattern = match entry.kind {
+                RuleKind::Exact => None,
+                RuleKind::Regex => Some(entry.from.clone()),
+                RuleKind::Glob => Some(glob_to_regex(&entry.from)),
+            };
+            let pattern = pattern
+                .map(|p| Regex::new(&p).map_err(|e| format!("rule {}: {}", i + 1, e)))
+                .transpose()?;
+            let query = entry.query
+                .map(|q| Regex::new(&q).map_err(|e| format!("rule {}: {}", i + 1, e)))
+                .transpose()?;
+
+            if pattern.is_none() {
+                rules.exact.entry(entry.from.clone()).or_default().push(i);
+            } else {
+                rules.patterns.push(i);
+            }
+            rules.rules.push(Rule {
+                pattern,
+                to: entry.to,
+                status,
+                host: entry.host.map(|h| h.to_ascii_lowercase()),
+                query,
+            });
+        }
+
+        rules.check_loops()?;
+        Ok(rules)
+    }
+
+    /// リダイレクト/リライトを適用する。pathは "/" から始まるデコード済みのパス
+    pub fn apply(&self, host: &str, path: &str, query: &str) -> RewriteResult {
+        let mut path = path.to_string();
+        let mut rewritten = false;
+
+        for _ in 0..MAX_REWRITES {
+            let condition_ok = |rule: &Rule| {
+                rule.host.as_deref().is_none_or(|h| h == host) && rule.query.as_ref().is_none_or(|q| q.is_match(query))
+            };
+            match self.find(condition_ok, &path) {
+                Some((index, target)) => match self.rules[index].status {
+                    Some(status) => return RewriteResult::Redirect(status, target),
+                    None => {
+                        path = target;
+                        rewritten = true;
+                    }
+                },
+                None if rewritten => return RewriteResult::Rewrite(path),
+                None => return RewriteResult::None,
+            }
+        }
+        // 置換先に $ を含むルールのループは起動時に検出できないのでここで止める
+        RewriteResult::TooManyRewrites(path)
+    }
+
+    /// 正規化済みのパス (先頭 "/" なし) にリライトを適用した、Routerが処理するパス
+    /// リダイレクトになる場合やリライト先を正規化できない場合はNone
+    pub fn rewritten_path(&self, req: &HttpRequest, path: &str, nfc: bool) -> Option<String> {
+        match self.apply(&request_host(req), &format!("/{}", path), req.query_string()) {
+            RewriteResult::Rewrite(target) => {
+                let target = target.split_once('?').map_or(target.as_str(), |(target, _)| target);
+                normalize_path(target, nfc).ok()
+            }
+            RewriteResult::None => Some(path.to_string()),
+            RewriteResult::Redirect(..) | RewriteResult::TooManyRewrites(_) => None,
+        }
+    }
+
+    /// pathに一致し、condition_okを満たす最初のルールの番号と置換後のパスを返す
+    fn find(&self, condition_ok: impl Fn(&Rule) -> bool, path: &str) -> Option<(usize, String)> {
+        if let Some(indices) = self.exact.get(path) {
+            if let Some(&index) = indices.iter().find(|&&i| condition_ok(&self.rules[i])) {
+                return Some((index, self.rules[index].to.clone()));
+            }
+        }
+
+        self.patterns.iter().find_map(|&index| {
+            let rule = &self.rules[index];
+            if !condition_ok(rule) {
+                return None;
+            }
+            let captures = rule.pattern.as_ref()?.captures(path)?;
+            let mut target = String::new();
+            captures.expand(&rule.to, &mut target);
+            Some((index, target))
+        })
+    }
+
+    /// 置換先が固定のルールを辿ってループしないか確認する (置換先に $ を含むルールはapplyで止める)
+    /// 最初のルールのhost/query条件が満たされ、それと違う条件のルールは一致しないリクエストとして辿る
+    /// (別のホストでしか一致しないルール同士はループにならず、条件付きのルールが条件なしのルールを隠さない)
+    fn check_loops(&self) -> Result<(), String> {
+        for (start, rule) in self.rules.iter().enumerate() {
+            let same_conditions = |other: &Rule| {
+                other.host.as_ref().is_none_or(|h| rule.host.as_ref() == Some(h))
+                    && other.query.as_ref().is_none_or(|q| rule.query.as_ref().is_some_and(|r| r.as_str() == q.as_str()))
+            };
+            let mut visited = vec![start];
+            let mut chain = vec![rule.to.clone()];
+            let mut target = rule.to.clone();
+            while !target.contains('$') && target.starts_with('/') {
+                let Some((index, next_target)) = self.find(same_conditions, &target) else { break };
+                chain.push(next_target.clone());
+                if visited.contains(&index) {
+                    return Err(format!("rewrite rule loop detected: {}", chain.join(" -> ")));
+                }
+                visited.push(index);
+                target = next_target;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// リダイレクトのLocation。置換後のパスはデコード済みなのでエンコードし、リクエストのクエリを後ろに付ける
+/// "https://example.com/path" のような置換先はホストまでをそのまま使う
+pub fn redirect_location(target: &str, query: &str) -> String {
+    let (target, target_query) = target.split_once('?').unwrap_or((target, ""));
+    let (origin, path) = match target.split_once("://") {
+        Some((scheme, rest)) => {
+            let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+            (format!("{}://{}", scheme, host), path)
+        }
+        None => (String::new(), target.trim_start_matches('/')),
+    };
+    let query: Vec<String> = [utf8_percent_encode(target_query, QUERY).to_string(), query.to_string()]
+        .into_iter()
+        .filter(|q| !q.is_empty())
+        .collect();
+    if query.is_empty() {
+        format!("{}{}", origin, encode_path(path))
+    } else {
+        format!("{}{}?{}", origin, encode_path(path), query.join("&"))
+    }
+}
+
+/// "*" は1セグメント、"**" は複数セグメントに一致させ、それぞれ $1, $2... でキャプチャする
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str("(.*)");
+            }
+            '*' => regex.push_str("([^/]*)"),
+            '?' => regex.push_str("([^/])"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(toml: &str) -> RewriteRules {
+        RewriteRules::parse(toml).unwrap()
+    }
+
+    fn apply(rules: &RewriteRules, host: &str, path: &str, query: &str) -> String {
+        match rules.apply(host, path, query) {
+            RewriteResult::Redirect(status, location) => format!("{} {}", status, location),
+            RewriteResult::Rewrite(path) => format!("rewrite {}", path),
+            RewriteResult::TooManyRewrites(path) => format!("too many {}", path),
+            RewriteResult::None => "none".to_string(),
+        }
+    }
+
+    #[test]
+    fn glob_captures_segments() {
+        let rules = rules(r#"
+            [[rule]]
+            from = "/blog/*/*"
+            kind = "glob"
+            to = "/posts/$1/$2"
+            status = 308
+
+            [[rule]]
+            from = "/docs/**"
+            kind = "glob"
+            to = "/manual/$1"
+            rewrite = true
+
+            [[rule]]
+            from = "/v?/api"
+            kind = "glob"
+            to = "/api/v$1"
+            rewrite = true
+        "#);
+        assert_eq!(apply(&rules, "x", "/blog/2024/hello", ""), "308 /posts/2024/hello");
+        // "*" は "/" をまたがない
+        assert_eq!(apply(&rules, "x", "/blog/2024/01/hello", ""), "none");
+        assert_eq!(apply(&rules, "x", "/docs/a/b/c.html", ""), "rewrite /manual/a/b/c.html");
+        assert_eq!(apply(&rules, "x", "/v2/api", ""), "rewrite /api/v2");
+        assert_eq!(apply(&rules, "x", "/v10/api", ""), "none");
+        // 正規表現の記号はエスケープする
+        assert_eq!(glob_to_regex("/a.b/*"), r"^/a\.b/([^/]*)$");
+    }
+
+    #[test]
+    fn host_and_query_conditions() {
+        let rules = rules(r#"
+            [[rule]]
+            from = "/"
+            to = "https://new.example.com/"
+            host = "OLD.example.com"
+
+            [[rule]]
+            from = "/search"
+            to = "/ja/search"
+            query = "(^|&)lang=ja(&|$)"
+            rewrite = true
+        "#);
+        assert_eq!(apply(&rules, "old.example.com", "/", ""), "301 https://new.example.com/");
+        assert_eq!(apply(&rules, "example.com", "/", ""), "none");
+        assert_eq!(apply(&rules, "x", "/search", "q=1&lang=ja"), "rewrite /ja/search");
+        assert_eq!(apply(&rules, "x", "/search", "lang=jan"), "none");
+    }
+
+    #[test]
+    fn rejects_unsupported_status() {
+        let err = RewriteRules::parse("[[rule]]\nfrom = \"/a\"\nto = \"/b\"\nstatus = 200\n").err().unwrap();
+        assert!(err.contains("unsupported redirect status 200"), "{}", err);
+    }
+
+    #[test]
+    fn redirect_location_encodes_path_and_appends_query() {
+        assert_eq!(redirect_location("/new page/ü", ""), "/new%20page/%C3%BC");
+        assert_eq!(redirect_location("/new", "a=1&b=2"), "/new?a=1&b=2");
+        assert_eq!(redirect_location("/new?x=1 2", "a=1"), "/new?x=1%202&a=1");
+        assert_eq!(redirect_location("/100%", ""), "/100%25");
+        assert_eq!(redirect_location("/a#b", ""), "/a%23b");
+        assert_eq!(redirect_location("https://example.com/new path", "a=1"), "https://example.com/new%20path?a=1");
+        assert_eq!(redirect_location("https://example.com", ""), "https://example.com/");
+    }
+
+    #[test]
+    fn detects_loops() {
+        let err = RewriteRules::parse(r#"
+            [[rule]]
+            from = "/a"
+            to = "/b"
+            rewrite = true
+
+            [[rule]]
+            from = "/b"
+            to = "/c"
+
+            [[rule]]
+            from = "/c"
+            to = "/a"
+            rewrite = true
+        "#).err().unwrap();
+        assert!(err.contains("loop"), "{}", err);
+
+        let err = RewriteRules::parse("[[rule]]\nfrom = \"/a\"\nto = \"/a\"\n").err().unwrap();
+        assert!(err.contains("loop"), "{}", err);
+    }
+
+    #[test]
+    fn rules_for_different_hosts_do_not_loop() {
+        rules(r#"
+            [[rule]]
+            from = "/a"
+            to = "/b"
+            host = "x.com"
+
+            [[rule]]
+            from = "/b"
+            to = "/a"
+            host = "y.com"
+        "#);
+    }
+
+    #[test]
+    fn conditional_rule_does_not_hide_loop() {
+        // /b は x.com でなければ /a に戻る
+        let err = RewriteRules::parse(r#"
+            [[rule]]
+            from = "/a"
+            to = "/b"
+            rewrite = true
+
+            [[rule]]
+            from = "/b"
+            to = "/c"
+            host = "x.com"
+
+            [[rule]]
+            from = "/b"
+            to = "/a"
+            rewrite = true
+        "#).err().unwrap();
+        assert!(err.contains("/b -> /a -> /b"), "{}", err);
+
+        // 同じホストの条件なら辿る
+        let err = RewriteRules::parse(r#"
+            [[rule]]
+            from = "/a"
+            to = "/b"
+            host = "x.com"
+
+            [[rule]]
+            from = "/b"
+            to = "/a"
+            host = "x.com"
+        "#).err().unwrap();
+        assert!(err.contains("loop"), "{}", err);
+    }
+
+    #[test]
+    fn capture_loops_stop_at_runtime() {
+        let rules = rules(r#"
+            [[rule]]
+            from = "^/x/(.*)$"
+            kind = "regex"
+            to = "/x/$1"
+            rewrite = true
+        "#);
+        assert_eq!(apply(&rules, "x", "/x/y", ""), "too many /x/y");
+    }
+}
@@ -1,57 +1,400 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use actix_web::{HttpRequest, HttpResponse};
+use actix_web::{http::{header, Method, StatusCode}, HttpRequest, HttpResponse};
 use bytes::Bytes;
-use tera::Tera;
+use serde_json::{json, Value};
 
-use crate::sys::init::AppConfig;
+use super::autoindex::AutoIndex;
+use super::basic_auth::BasicAuth;
+use super::client_info::ClientInfo;
+use super::csrf::{inject_token, with_token};
+use super::front_matter::page_headers;
+use super::jwt_auth::JwtClaims;
+use super::session::Session;
+use super::path::{encode_path, normalize_path};
+use super::render_cache::RenderCache;
+use super::security_headers::{csp_nonce, inject_nonce, NONCE_PLACEHOLDER};
+use super::rewrite::{redirect_location, RewriteResult, RewriteRules};
+use crate::sys::app_set::request_host;
+use crate::sys::init::{AppConfig, CleanUrls, SiteConfig};
+use crate::sys::site::SiteContent;
+
+/// 静的ファイルに対して受け付けるメソッド (Allowヘッダーの値)
+pub const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
 
 pub struct Router {
-    pub template: Tera,
-    pub static_cache: HashMap<String, Bytes>,
+    pub content: SiteContent,
+    pub cache_control: Option<String>,
+    pub unicode_nfc: bool,
+    pub clean_urls: CleanUrls,
+    pub spa_fallback: Option<String>,
+    pub rewrite_rules: Arc<RewriteRules>,
+    pub basic_auth: Arc<BasicAuth>,
+    pub autoindex: AutoIndex,
+    pub render_cache: RenderCache,
+}
+
+/// リクエストパスの解決結果
+#[derive(Debug, PartialEq)]
+enum Resolved {
+    File(String),
+    Redirect(String),
+    // 一覧表示するディレクトリ
+    AutoIndex(String),
+    NotFound,
 }
 
 impl Router {
-    pub fn new(app_config: &AppConfig, template: Tera, static_cach: HashMap<String, Bytes>) -> Self {
-        let router = Router {
-            template,
-            static_cache: static_cach,
-        };
-        router
+    pub fn new(
+        app_config: &AppConfig,
+        site_config: &SiteConfig,
+        content: SiteContent,
+        rewrite_rules: Arc<RewriteRules>,
+        basic_auth: Arc<BasicAuth>,
+    ) -> Self {
+        Router {
+            content,
+            cache_control: site_config.cache_control.clone(),
+            unicode_nfc: app_config.unicode_nfc,
+            clean_urls: site_config.clean_urls,
+            spa_fallback: site_config.spa_fallback.clone(),
+            rewrite_rules,
+            basic_auth,
+            autoindex: AutoIndex::new(&site_config.autoindex),
+            render_cache: RenderCache::new(&app_config.render_cache, app_config.render_cache_ttl),
+        }
     }
 
     pub async fn handle_request(&self, req: HttpRequest) -> HttpResponse {
-        let mut path = req.path().trim_start_matches('/').to_string();
+        let mut path = match normalize_path(req.path(), self.unicode_nfc) {
+            Ok(path) => path,
+            Err(err) => {
+                log::debug!("Bad request path: {} ({:?})", req.path(), err);
+                return HttpResponse::BadRequest().finish();
+            }
+        };
+
+        // キャッシュを引く前にリダイレクト/リライトルールを適用する
+        match self.rewrite_rules.apply(&request_host(&req), &format!("/{}", path), req.query_string()) {
+            RewriteResult::Redirect(status, location) => {
+                return HttpResponse::build(StatusCode::from_u16(status).unwrap_or(StatusCode::MOVED_PERMANENTLY))
+                    .insert_header(("Location", redirect_location(&location, req.query_string())))
+                    .finish();
+            }
+            RewriteResult::Rewrite(target) => {
+                let (target, _query) = target.split_once('?').unwrap_or((&target, ""));
+                path = match normalize_path(target, self.unicode_nfc) {
+                    Ok(path) => path,
+                    Err(_) => return HttpResponse::InternalServerError().finish(),
+                };
+            }
+            RewriteResult::TooManyRewrites(last) => {
+                eprintln!("Too many rewrites: {} -> {} (rewrite rule loop?)", req.path(), last);
+                return HttpResponse::InternalServerError().finish();
+            }
+            RewriteResult::None => {}
+        }
 
-        if path.ends_with('/') {
-            path.push_str("index.html");
+        if req.method() == Method::OPTIONS {
+            return HttpResponse::NoContent()
+                .insert_header(("Allow", ALLOWED_METHODS))
+                .finish();
         }
 
-        println!("Request path: {}", path);
+        // リライト後のパスで判定する (エンコードや別名での回避を防ぐ)
+        if let Some(response) = self.basic_auth.check(&req, &path).await.into_response() {
+            return response;
+        }
 
-        if let Some(content) = self.static_cache.get(&path) {
-            self.handle_static_file(&path, content)
-        } else {
-            HttpResponse::NotFound().body("404 Not Found")
+        match self.resolve(&path) {
+            Resolved::File(key) => {
+                let content = &self.content.static_cache[&key];
+                self.handle_static_file(&req, &key, content)
+            }
+            Resolved::Redirect(location) => {
+                let location = match req.query_string() {
+                    "" => location,
+                    query => format!("{}?{}", location, query),
+                };
+                HttpResponse::MovedPermanently()
+                    .insert_header(("Location", location))
+                    .finish()
+            }
+            Resolved::AutoIndex(dir) => {
+                let content = &self.content;
+                self.autoindex.render(&req, &dir, &content.static_cache, &content.modified, &content.template, &self.basic_auth)
+            }
+            Resolved::NotFound => HttpResponse::NotFound().body("404 Not Found"),
         }
     }
 
-    fn handle_static_file(&self, path: &str, content: &Bytes) -> HttpResponse {
-        if path.ends_with(".html") {
-            self.render_template(path)
+    /// 正規化済みのパスをstatic_cacheのキーかリダイレクト先に解決する
+    fn resolve(&self, path: &str) -> Resolved {
+        let exists = |key: &str| self.content.static_cache.contains_key(key);
+        let is_dir = path.is_empty() || path.ends_with('/');
+        let index = format!("{}index.html", path);
+
+        if is_dir && exists(&index) {
+            return Resolved::File(index);
+        }
+        if !is_dir && exists(path) {
+            // 正規URLでない .html 直指定はリダイレクトする
+            match self.clean_urls {
+                CleanUrls::Html if path.ends_with(".html") && !path.ends_with("index.html") => {
+                    return Resolved::Redirect(encode_path(path.trim_end_matches(".html")));
+                }
+                CleanUrls::Directory if path == "index.html" || path.ends_with("/index.html") => {
+                    return Resolved::Redirect(encode_path(path.trim_end_matches("index.html")));
+                }
+                _ => return Resolved::File(path.to_string()),
+            }
+        }
+
+        if self.clean_urls == CleanUrls::Html {
+            let stem = path.trim_end_matches('/');
+            let html = format!("{}.html", stem);
+            if !stem.is_empty() && exists(&html) {
+                return if is_dir {
+                    Resolved::Redirect(encode_path(stem))
+                } else {
+                    Resolved::File(html)
+                };
+            }
+        }
+        // どちらのモードでもディレクトリは末尾 "/" 付きを正規URLとする
+        if self.clean_urls != CleanUrls::Off && !is_dir && exists(&format!("{}/index.html", path)) {
+            return Resolved::Redirect(encode_path(&format!("{}/", path)));
+        }
+
+        if is_dir && self.autoindex.enabled(path) {
+            return Resolved::AutoIndex(path.to_string());
+        }
+
+        // SPAモード: 拡張子のないパスはエントリーHTMLを返す (.jsや.cssの欠落は404のまま)
+        if let Some(entry) = &self.spa_fallback {
+            let last_segment = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+            if !last_segment.contains('.') && exists(entry) {
+                return Resolved::File(entry.clone());
+            }
+        }
+
+        Resolved::NotFound
+    }
+
+    fn handle_static_file(&self, req: &HttpRequest, path: &str, content: &Bytes) -> HttpResponse {
+        let etag = self.content.etags.get(path).map(|e| e.as_str());
+        if let Some(html) = self.content.rendered.get(path) {
+            match self.personalize(req, html) {
+                Some(html) => self.respond(req, "text/html; charset=utf-8", html, None, true, &Value::Null),
+                None => self.respond(req, "text/html; charset=utf-8", html.clone(), etag, false, &Value::Null),
+            }
+        } else if path.ends_with(".html") {
+            self.render_template(req, path)
         } else {
             let mime_type = mime_guess::from_path(path).first_or_octet_stream();
-            HttpResponse::Ok()
-                .content_type(mime_type.as_ref())
-                .body(content.clone())
+            self.respond(req, mime_type.as_ref(), content.clone(), etag, false, &Value::Null)
         }
     }
 
-    fn render_template(&self, path: &str) -> HttpResponse {
-        let rendered = self.template.render(path, &tera::Context::new());
-        match rendered {
-            Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
-            Err(_) => HttpResponse::InternalServerError().body("Template rendering error"),
+    /// 描画済みのHTMLのnonce、CSRFトークン、クライアント情報のプレースホルダーをリクエストの値に置き換える
+    /// 置き換えた場合はレスポンスごとに内容が変わるのでSomeを返す
+    fn personalize(&self, req: &HttpRequest, body: &Bytes) -> Option<Bytes> {
+        let with_nonce = inject_nonce(req, body);
+        let text = std::str::from_utf8(with_nonce.as_ref().unwrap_or(body)).ok()?;
+        let with_token = inject_token(req, text);
+        let text = with_token.as_deref().unwrap_or(text);
+        match ClientInfo::inject(req, text).or(with_token) {
+            Some(text) => Some(Bytes::from(text)),
+            None => with_nonce,
         }
     }
-}
\ No newline at end of file
+
+    /// Cache-ControlとETagを付けて返す (If-None-Matchが一致すれば304)
+    /// privateはリクエストごとの内容 (nonce、CSRFトークン、クレーム、セッション) を含むか
+    /// pageはフロントマター。headers があればレスポンスヘッダーにする
+    fn respond(&self, req: &HttpRequest, content_type: &str, body: Bytes, etag: Option<&str>, private: bool, page: &Value) -> HttpResponse {
+        let not_modified = etag.is_some_and(|etag| {
+            req.headers().get(header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"))
+        });
+
+        let mut response = if not_modified { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+        if let Some(etag) = etag {
+            response.insert_header((header::ETAG, etag));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            response.insert_header((header::CACHE_CONTROL, cache_control.as_str()));
+        }
+        // 使えないヘッダーはサイトの読み込み時にログに出しているので飛ばす
+        for header in page_headers(page).0 {
+            response.insert_header(header);
+        }
+        // 共有キャッシュに他の人の内容を保存させない (フロントマターの指定より優先する)
+        if private {
+            response.insert_header((header::CACHE_CONTROL, "private, no-store"));
+        }
+        if not_modified {
+            return response.finish();
+        }
+        response.content_type(content_type).body(body)
+    }
+
+    /// フロントマターの headers はレスポンスヘッダーにする
+    /// キャッシュするテンプレートはnonceなどをプレースホルダーで描画しておき、レスポンスごとに置き換える
+    fn render_template(&self, req: &HttpRequest, path: &str) -> HttpResponse {
+        let page = self.content.page_meta.get(path).unwrap_or(&Value::Null);
+        // JWTのクレームとセッションはリクエストごとに違うのでキャッシュしない
+        let claims = JwtClaims::get(req);
+        let session = Session::get(req);
+        if claims.is_some() || !session.is_empty() || !self.render_cache.is_cacheable(path, page) {
+            // リクエストの値をそのまま渡して描画する
+            // (描画後に置き換えると、クレームなどに含まれるプレースホルダーの文字列にも本物の値が入る)
+            let nonce = csp_nonce(req);
+            let client = ClientInfo::get(req);
+            let (rendered, token_used) = with_token(req, || {
+                self.render_page(path, &nonce, &json!(client), claims.as_ref(), Some(&session))
+            });
+            return match rendered {
+                Ok(body) => {
+                    // nonceやクライアントIPを含むページは共有キャッシュに保存させない
+                    let per_request = token_used || (!nonce.is_empty() && body.contains(&nonce)) || body.contains(&client.ip.to_string());
+                    let private = claims.is_some() || !session.is_empty() || per_request;
+                    self.respond(req, "text/html", Bytes::from(body), None, private, page)
+                }
+                Err(_) => HttpResponse::InternalServerError().body("Template rendering error"),
+            };
+        }
+
+        let cached = match self.render_cache.get(path) {
+            Some(cached) => cached,
+            None => match self.render_page(path, NONCE_PLACEHOLDER, &ClientInfo::placeholder_context(), None, None) {
+                Ok(body) => self.render_cache.insert(path, Bytes::from(body)),
+                Err(_) => return HttpResponse::InternalServerError().body("Template rendering error"),
+            },
+        };
+        match self.personalize(req, &cached.body) {
+            Some(body) => self.respond(req, "text/html", body, None, true, page),
+            None => self.respond(req, "text/html", cached.body, Some(&cached.etag), false, page),
+        }
+    }
+
+    /// HTMLテンプレートを描画する
+    /// フロントマターの値はトップレベルと page に、_data/ の内容は data として渡す
+    /// nonceとclientはキャッシュする場合はプレースホルダー
+    /// claimsは検証済みのJWTのクレーム (なければnull)、sessionはセッションの内容 (なければ空)
+    pub fn render_page(&self, path: &str, nonce: &str, client: &Value, claims: Option<&Value>, session: Option<&Session>) -> tera::Result<String> {
+        let page = self.content.page_meta.get(path).unwrap_or(&Value::Null);
+        let mut context = tera::Context::new();
+        if let Some(object) = page.as_object() {
+            for (key, value) in object {
+                context.insert(key, value);
+            }
+        }
+        context.insert("page", page);
+        context.insert("data", &self.content.data);
+        context.insert("csp_nonce", nonce);
+        context.insert("client", client);
+        context.insert("claims", &claims);
+        context.insert("session", &session.map(|s| s.data()).unwrap_or_else(|| Value::Object(Default::default())));
+        self.content.template.render(path, &context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use actix_web::{body::to_bytes, test::TestRequest, HttpMessage};
+    use tera::Tera;
+
+    use super::super::client_info::{CLIENT_IP_PLACEHOLDER, CLIENT_SCHEME_PLACEHOLDER};
+    use super::super::csrf::CSRF_PLACEHOLDER;
+    use super::super::template_helpers;
+    use crate::sys::init::RateLimitConfig;
+
+    /// filesのキーだけを持つサイトのRouter (templatesはHTMLテンプレートとして登録する)
+    fn router(files: &[&str], templates: &[(&str, &str)], clean_urls: CleanUrls, spa_fallback: Option<&str>) -> Router {
+        let app_config = AppConfig::new();
+        let mut static_cache: HashMap<String, Bytes> = files.iter().map(|key| (key.to_string(), Bytes::new())).collect();
+        static_cache.extend(templates.iter().map(|(key, source)| (key.to_string(), Bytes::from(source.to_string()))));
+        let mut template = Tera::default();
+        template_helpers::register(&mut template, &static_cache, &Value::Null, &app_config);
+        template.add_raw_templates(templates.iter().copied()).unwrap();
+        let site_config = SiteConfig { clean_urls, spa_fallback: spa_fallback.map(|s| s.to_string()), ..app_config.default_site() };
+        let content = SiteContent {
+            template,
+            static_cache,
+            modified: HashMap::new(),
+            rendered: HashMap::new(),
+            page_meta: HashMap::new(),
+            data: Value::Null,
+            etags: HashMap::new(),
+        };
+        let failure_limit = RateLimitConfig { burst: 1, per_second: 1.0, groups: Vec::new(), max_clients: 1 };
+        Router::new(&app_config, &site_config, content, Arc::default(), Arc::new(BasicAuth::new(&[], &failure_limit)))
+    }
+
+    #[actix_web::test]
+    async fn request_values_are_not_replaced_in_per_request_pages() {
+        let router = router(&[], &[("page.html", "{{ claims.name }}|{{ client.ip }}|{{ csrf_token() }}|{{ csp_nonce }}")], CleanUrls::Off, None);
+        let req = TestRequest::get().uri("/page.html").peer_addr("192.0.2.7:40000".parse().unwrap()).to_http_request();
+        // クレームにプレースホルダーの文字列が含まれていても、本物の値に置き換えない
+        let name = [NONCE_PLACEHOLDER, CSRF_PLACEHOLDER, CLIENT_IP_PLACEHOLDER, CLIENT_SCHEME_PLACEHOLDER].join(" ");
+        req.extensions_mut().insert(JwtClaims(json!({ "name": name })));
+
+        let res = router.handle_request(req).await;
+        assert_eq!(res.headers().get(header::CACHE_CONTROL).unwrap(), "private, no-store");
+        let body = to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, format!("{}|192.0.2.7||", name));
+    }
+
+    #[test]
+    fn resolves_clean_urls() {
+        let files = ["index.html", "about.html", "docs/index.html", "docs/a.html", "logo.png"];
+        let file = |key: &str| Resolved::File(key.to_string());
+        let redirect = |location: &str| Resolved::Redirect(location.to_string());
+
+        let off = router(&files, &[], CleanUrls::Off, None);
+        assert_eq!(off.resolve(""), file("index.html"));
+        assert_eq!(off.resolve("docs/"), file("docs/index.html"));
+        assert_eq!(off.resolve("about.html"), file("about.html"));
+        assert_eq!(off.resolve("about"), Resolved::NotFound);
+        assert_eq!(off.resolve("docs"), Resolved::NotFound);
+
+        let html = router(&files, &[], CleanUrls::Html, None);
+        assert_eq!(html.resolve("about"), file("about.html"));
+        assert_eq!(html.resolve("about.html"), redirect("/about"));
+        assert_eq!(html.resolve("about/"), redirect("/about"));
+        assert_eq!(html.resolve("docs/a"), file("docs/a.html"));
+        assert_eq!(html.resolve("docs"), redirect("/docs/"));
+        assert_eq!(html.resolve("docs/"), file("docs/index.html"));
+        assert_eq!(html.resolve("docs/index.html"), file("docs/index.html"));
+        assert_eq!(html.resolve("logo.png"), file("logo.png"));
+
+        let directory = router(&files, &[], CleanUrls::Directory, None);
+        assert_eq!(directory.resolve("docs"), redirect("/docs/"));
+        assert_eq!(directory.resolve("docs/"), file("docs/index.html"));
+        assert_eq!(directory.resolve("docs/index.html"), redirect("/docs/"));
+        assert_eq!(directory.resolve("index.html"), redirect("/"));
+        assert_eq!(directory.resolve("about"), Resolved::NotFound);
+    }
+
+    #[test]
+    fn falls_back_to_spa_entry() {
+        let files = ["app.html", "assets/app.js"];
+        let spa = router(&files, &[], CleanUrls::Off, Some("app.html"));
+        let file = |key: &str| Resolved::File(key.to_string());
+        assert_eq!(spa.resolve(""), file("app.html"));
+        assert_eq!(spa.resolve("users/42"), file("app.html"));
+        assert_eq!(spa.resolve("users/42/"), file("app.html"));
+        assert_eq!(spa.resolve("assets/app.js"), file("assets/app.js"));
+        // 拡張子のある欠落ファイルは404のまま
+        assert_eq!(spa.resolve("assets/missing.js"), Resolved::NotFound);
+
+        // エントリーHTMLがなければフォールバックしない
+        let missing = router(&[], &[], CleanUrls::Off, Some("app.html"));
+        assert_eq!(missing.resolve("users/42"), Resolved::NotFound);
+    }
+}
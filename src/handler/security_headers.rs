@@ -0,0 +1,157 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web, HttpMessage, HttpRequest,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use glob::Pattern;
+
+use crate::sys::app_set::AppSet;
+use crate::sys::init::{AppConfig, SecurityHeaderOverride};
+
+/// 起動時に描画するHTML(Markdownやキャッシュしたテンプレート)に埋め込み、レスポンス時にnonceへ置き換える
+pub const NONCE_PLACEHOLDER: &str = "__csp_nonce_placeholder__";
+
+/// リクエストごとのCSP nonce
+#[derive(Clone)]
+struct CspNonce(String);
+
+// (ヘッダー名, 値)。値がNoneなら付けない
+type HeaderOverrides = Vec<(HeaderName, Option<String>)>;
+
+/// パスごとの上書きを解決済みのセキュリティヘッダー設定
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, String)>,
+    overrides: Vec<(Pattern, HeaderOverrides)>,
+}
+
+impl SecurityHeaders {
+    pub fn new(app_config: &AppConfig) -> Self {
+        SecurityHeaders {
+            headers: app_config.security_headers.iter()
+                .map(|(name, value)| (header_name(name), value.clone()))
+                .collect(),
+            overrides: app_config.security_header_overrides.iter()
+                .map(|SecurityHeaderOverride { path, headers }| {
+                    let pattern = Pattern::new(path).expect("Invalid security header override pattern");
+                    let headers = headers.iter().map(|(name, value)| (header_name(name), value.clone())).collect();
+                    (pattern, headers)
+                })
+                .collect(),
+        }
+    }
+
+    /// パスに適用するヘッダー (後の上書きが優先、Noneなら付けない)
+    /// pathsは正規化したパスと書き換え後のパスで、どれかに一致すれば上書きする
+    fn for_paths<S: AsRef<str>>(&self, paths: &[S]) -> HeaderOverrides {
+        let mut headers: HeaderOverrides = self.headers.iter()
+            .map(|(name, value)| (name.clone(), Some(value.clone())))
+            .collect();
+        for (pattern, overrides) in &self.overrides {
+            if !paths.iter().any(|path| pattern.matches(path.as_ref())) {
+                continue;
+            }
+            for (name, value) in overrides {
+                match headers.iter_mut().find(|(n, _)| n == name) {
+                    Some(entry) => entry.1 = value.clone(),
+                    None => headers.push((name.clone(), value.clone())),
+                }
+            }
+        }
+        headers
+    }
+}
+
+fn header_name(name: &str) -> HeaderName {
+    HeaderName::from_bytes(name.as_bytes()).expect("Invalid security header name")
+}
+
+/// nonceを発行し、Router/ErrHandlerなどのレスポンスにセキュリティヘッダーを付ける
+/// レスポンスに既にあるヘッダー(フロントマターの headers など)は上書きしない
+pub async fn middleware(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let nonce = STANDARD.encode(rand::random::<[u8; 16]>());
+    req.extensions_mut().insert(CspNonce(nonce.clone()));
+    let Some(app_set) = req.app_data::<web::Data<AppSet>>().cloned() else {
+        return next.call(req).await;
+    };
+    // 上書きは正規化したパスで決める (正規化できないパスは生のパス)
+    let mut paths: Vec<String> = app_set.request_paths(req.request()).iter().map(|path| format!("/{}", path)).collect();
+    if paths.is_empty() {
+        paths.push(req.path().to_string());
+    }
+
+    let mut res = next.call(req).await?;
+
+    let headers = res.headers_mut();
+    for (name, value) in app_set.security_headers.for_paths(&paths) {
+        let Some(value) = value else { continue };
+        if headers.contains_key(&name) {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(&value.replace("{nonce}", &nonce)) {
+            headers.insert(name, value);
+        }
+    }
+    Ok(res)
+}
+
+/// テンプレートに csp_nonce として渡す値 (ミドルウェアを通っていなければ空)
+pub fn csp_nonce(req: &HttpRequest) -> String {
+    req.extensions().get::<CspNonce>().map(|n| n.0.clone()).unwrap_or_default()
+}
+
+/// 事前に描画したHTMLのプレースホルダーをnonceに置き換える
+/// 置き換えた場合はレスポンスごとに内容が変わるのでSomeを返す
+pub fn inject_nonce(req: &HttpRequest, body: &Bytes) -> Option<Bytes> {
+    let text = std::str::from_utf8(body).ok()?;
+    if !text.contains(NONCE_PLACEHOLDER) {
+        return None;
+    }
+    Some(Bytes::from(text.replace(NONCE_PLACEHOLDER, &csp_nonce(req))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware::from_fn, App, HttpResponse};
+
+    fn header<B>(res: &ServiceResponse<B>, name: &str) -> Option<String> {
+        res.headers().get(name).map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[actix_web::test]
+    async fn overrides_match_normalized_path() {
+        let app_config = AppConfig {
+            security_header_overrides: vec![
+                SecurityHeaderOverride {
+                    path: "/private/*".to_string(),
+                    headers: vec![("X-Robots-Tag".to_string(), Some("noindex".to_string()))],
+                },
+                SecurityHeaderOverride {
+                    path: "/embed/*".to_string(),
+                    headers: vec![("X-Frame-Options".to_string(), None)],
+                },
+            ],
+            ..AppConfig::new()
+        };
+        let app_set = web::Data::new(AppSet::new(app_config).await);
+        let app = init_service(App::new().wrap(from_fn(middleware)).app_data(app_set).default_service(web::to(HttpResponse::Ok))).await;
+
+        for uri in ["/private/a.html", "//private/a.html", "/%70rivate/a.html", "/private//a.html"] {
+            let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(header(&res, "x-robots-tag").as_deref(), Some("noindex"), "{}", uri);
+            assert_eq!(header(&res, "x-frame-options").as_deref(), Some("DENY"), "{}", uri);
+        }
+        let res = call_service(&app, TestRequest::get().uri("/%65mbed/a.html").to_request()).await;
+        assert_eq!(header(&res, "x-frame-options"), None);
+        assert_eq!(header(&res, "x-content-type-options").as_deref(), Some("nosniff"));
+
+        let res = call_service(&app, TestRequest::get().uri("/index.html").to_request()).await;
+        assert_eq!(header(&res, "x-robots-tag"), None);
+        assert!(header(&res, "content-security-policy").unwrap().contains("'nonce-"));
+    }
+}
@@ -0,0 +1,504 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::future::{ready, Ready};
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use actix_web::{
+    body::MessageBody,
+    cookie::{time, Cookie, CookieJar, Key},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    middleware::Next,
+    web, FromRequest, HttpMessage, HttpRequest,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha512};
+
+use crate::sys::app_set::AppSet;
+use crate::sys::init::{SessionConfig, SessionStoreKind};
+use crate::sys::privilege::current_path;
+
+// 最終アクセス日時を更新する間隔(秒)。毎回保存し直さないようにする
+const TOUCH_INTERVAL: i64 = 60;
+// メモリストアが期限切れのセッションを掃除する件数
+const MEMORY_PURGE_THRESHOLD: usize = 10_000;
+
+/// 保存するセッションの内容
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub data: Map<String, Value>,
+    // 作成日時と最終アクセス日時 (UNIX時間)
+    pub created: i64,
+    pub accessed: i64,
+}
+
+impl SessionRecord {
+    fn new() -> Self {
+        let now = Utc::now().timestamp();
+        SessionRecord { data: Map::new(), created: now, accessed: now }
+    }
+
+    fn is_expired(&self, idle_timeout: u64, absolute_timeout: u64) -> bool {
+        let now = Utc::now().timestamp();
+        now - self.accessed > idle_timeout as i64 || now - self.created > absolute_timeout as i64
+    }
+}
+
+/// サーバー側でセッションを保存するストア (Cookieには署名したIDだけを入れる)
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Option<SessionRecord>;
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()>;
+    fn remove(&self, id: &str);
+}
+
+/// プロセス内に保持するストア (再起動で消える)
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+    idle_timeout: u64,
+    absolute_timeout: u64,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionRecord> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MEMORY_PURGE_THRESHOLD && !sessions.contains_key(id) {
+            sessions.retain(|_, r| !r.is_expired(self.idle_timeout, self.absolute_timeout));
+        }
+        sessions.insert(id.to_string(), record.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+/// ディレクトリにセッションごとのJSONファイルを置くストア
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // IDは署名を検証済みだが、念のため16進数以外はパスにしない
+        id.chars().all(|c| c.is_ascii_hexdigit()).then(|| current_path(&self.dir).join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> Option<SessionRecord> {
+        let content = fs::read(self.path(id)?).ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let path = self.path(id).ok_or_else(|| io::Error::other("invalid session id"))?;
+        // 書きかけのファイルを読まないように一時ファイルから置き換える
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(record)?)?;
+        fs::rename(tmp, path)
+    }
+
+    fn remove(&self, id: &str) {
+        if let Some(path) = self.path(id) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+enum Backend {
+    // Cookieにセッションの内容を暗号化して入れる
+    Cookie,
+    // Cookieには署名したIDを入れ、内容はストアに置く
+    Store(Box<dyn SessionStore>),
+}
+
+/// Cookieの署名/暗号化とストアをまとめたもの
+pub struct Sessions {
+    config: SessionConfig,
+    // 先頭の鍵で署名/暗号化し、残りは検証だけに使う (鍵のローテーション用)
+    keys: Vec<Key>,
+    backend: Backend,
+}
+
+impl Sessions {
+    pub fn new(config: &SessionConfig) -> Result<Self, String> {
+        if config.keys.is_empty() {
+            return Err("session: at least one key is required".to_string());
+        }
+        if let Some(short) = config.keys.iter().find(|k| k.len() < 32) {
+            return Err(format!("session: key is too short ({} chars, 32 or more required)", short.len()));
+        }
+        let keys = config.keys.iter().map(|secret| Key::from(Sha512::digest(secret.as_bytes()).as_slice())).collect();
+
+        let backend = match &config.store {
+            SessionStoreKind::Cookie => Backend::Cookie,
+            SessionStoreKind::Memory => Backend::Store(Box::new(MemoryStore {
+                sessions: Mutex::new(HashMap::new()),
+                idle_timeout: config.idle_timeout,
+                absolute_timeout: config.absolute_timeout,
+            })),
+            SessionStoreKind::File(dir) => {
+                fs::create_dir_all(current_path(dir)).map_err(|e| format!("session: failed to create {}: {}", dir, e))?;
+                Backend::Store(Box::new(FileStore { dir: PathBuf::from(dir) }))
+            }
+        };
+        Ok(Sessions { config: config.clone(), keys, backend })
+    }
+
+    /// Cookieの値からセッションを読み込む (IDがあればそれも返す)
+    fn load(&self, value: &str) -> Option<(Option<String>, SessionRecord)> {
+        let name = &self.config.cookie_name;
+        let loaded = match &self.backend {
+            Backend::Cookie => {
+                let plain = self.keys.iter().find_map(|key| {
+                    let mut jar = CookieJar::new();
+                    jar.add_original(Cookie::new(name.clone(), value.to_string()));
+                    jar.private(key).get(name)
+                })?;
+                (None, serde_json::from_str(plain.value()).ok()?)
+            }
+            Backend::Store(store) => {
+                let id = self.keys.iter().find_map(|key| {
+                    let mut jar = CookieJar::new();
+                    jar.add_original(Cookie::new(name.clone(), value.to_string()));
+                    jar.signed(key).get(name)
+                })?;
+                let id = id.value().to_string();
+                let record = store.load(&id)?;
+                (Some(id), record)
+            }
+        };
+        if loaded.1.is_expired(self.config.idle_timeout, self.config.absolute_timeout) {
+            if let (Some(id), Backend::Store(store)) = (&loaded.0, &self.backend) {
+                store.remove(id);
+            }
+            return None;
+        }
+        Some(loaded)
+    }
+
+    /// 保存してSet-Cookieに入れるCookieを返す
+    fn save(&self, id: Option<String>, record: &SessionRecord) -> io::Result<Cookie<'static>> {
+        let name = self.config.cookie_name.clone();
+        let mut jar = CookieJar::new();
+        match &self.backend {
+            Backend::Cookie => {
+                jar.private_mut(&self.keys[0]).add(Cookie::new(name.clone(), serde_json::to_string(record)?));
+            }
+            Backend::Store(store) => {
+                let id = id.unwrap_or_else(|| hex::encode(rand::random::<[u8; 16]>()));
+                store.save(&id, record)?;
+                jar.signed_mut(&self.keys[0]).add(Cookie::new(name.clone(), id));
+            }
+        }
+        let value = jar.get(&name).map(|c| c.value().to_string()).unwrap_or_default();
+        Ok(self.cookie(value, time::Duration::seconds(self.config.idle_timeout as i64)))
+    }
+
+    fn cookie(&self, value: String, max_age: time::Duration) -> Cookie<'static> {
+        Cookie::build(self.config.cookie_name.clone(), value)
+            .path("/")
+            .same_site(self.config.same_site)
+            .secure(self.config.secure)
+            .http_only(self.config.http_only)
+            .max_age(max_age)
+            .finish()
+    }
+}
+
+struct SessionState {
+    id: Option<String>,
+    record: SessionRecord,
+    // Cookieから読み込んだセッションか
+    loaded: bool,
+    changed: bool,
+    purged: bool,
+}
+
+/// リクエスト中のセッション (ハンドラーの引数か Session::get で取得する)
+#[derive(Clone)]
+pub struct Session(Rc<RefCell<SessionState>>);
+
+#[allow(dead_code)] // ハンドラーから使うAPIなので組み込みのハンドラーでは未使用のものがある
+impl Session {
+    fn new(id: Option<String>, record: Option<SessionRecord>) -> Self {
+        let loaded = record.is_some();
+        Session(Rc::new(RefCell::new(SessionState {
+            id,
+            record: record.unwrap_or_else(SessionRecord::new),
+            loaded,
+            changed: false,
+            purged: false,
+        })))
+    }
+
+    /// リクエストのセッション (セッションが無効なら保存されない空のセッション)
+    pub fn get(req: &HttpRequest) -> Session {
+        req.extensions().get::<Session>().cloned().unwrap_or_else(|| Session::new(None, None))
+    }
+
+    pub fn value(&self, key: &str) -> Option<Value> {
+        self.0.borrow().record.data.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, value: impl Into<Value>) {
+        let mut state = self.0.borrow_mut();
+        state.record.data.insert(key.to_string(), value.into());
+        state.changed = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        let mut state = self.0.borrow_mut();
+        state.changed = true;
+        state.record.data.remove(key)
+    }
+
+    /// ログイン時などにIDを振り直す (セッション固定攻撃対策)
+    pub fn renew(&self) {
+        let mut state = self.0.borrow_mut();
+        state.purged = state.id.is_some();
+        state.changed = true;
+    }
+
+    /// ログアウト時などにセッションを破棄する
+    pub fn purge(&self) {
+        let mut state = self.0.borrow_mut();
+        state.record.data.clear();
+        state.purged = true;
+        state.changed = true;
+    }
+
+    /// テンプレートに session として渡す値
+    pub fn data(&self) -> Value {
+        Value::Object(self.0.borrow().record.data.clone())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().record.data.is_empty()
+    }
+}
+
+impl FromRequest for Session {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Session, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Session::get(req)))
+    }
+}
+
+/// Cookieからセッションを読み込み、変更があれば保存してSet-Cookieを付ける
+/// 空のセッションはCookieを発行しない
+pub async fn middleware(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let app_set = req.app_data::<web::Data<AppSet>>().cloned();
+    let Some(sessions) = app_set.as_ref().and_then(|a| a.sessions.as_ref()) else {
+        return next.call(req).await;
+    };
+
+    let cookie = req.cookie(&sessions.config.cookie_name);
+    let loaded = cookie.as_ref().and_then(|c| sessions.load(c.value()));
+    let had_cookie = cookie.is_some();
+    let session = match loaded {
+        Some((id, record)) => Session::new(id, Some(record)),
+        None => Session::new(None, None),
+    };
+    req.extensions_mut().insert(session.clone());
+
+    let mut res = next.call(req).await?;
+
+    let state = session.0.borrow();
+    let now = Utc::now().timestamp();
+    let remove_old = || {
+        if let (Some(id), Backend::Store(store)) = (&state.id, &sessions.backend) {
+            store.remove(id);
+        }
+    };
+    let mut record = state.record.clone();
+    record.accessed = now;
+
+    let set_cookie = if record.data.is_empty() {
+        // 空になったセッションと、期限切れや改ざんされたCookieは消す
+        if (state.loaded && state.changed) || (had_cookie && !state.loaded) {
+            remove_old();
+            Some(sessions.cookie(String::new(), time::Duration::ZERO))
+        } else {
+            None
+        }
+    } else if state.purged {
+        // IDを振り直す
+        remove_old();
+        Some(sessions.save(None, &record)?)
+    } else if state.changed || (state.loaded && now - state.record.accessed >= TOUCH_INTERVAL) {
+        Some(sessions.save(state.id.clone(), &record)?)
+    } else {
+        None
+    };
+
+    if let Some(cookie) = set_cookie {
+        if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::SameSite;
+
+    const OLD_KEY: &str = "old-session-key-0123456789abcdefghij";
+    const NEW_KEY: &str = "new-session-key-0123456789abcdefghij";
+
+    fn sessions(store: SessionStoreKind, keys: &[&str]) -> Sessions {
+        Sessions::new(&SessionConfig {
+            cookie_name: "session".to_string(),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            store,
+            same_site: SameSite::Lax,
+            secure: true,
+            http_only: true,
+            idle_timeout: 3600,
+            absolute_timeout: 86400,
+        }).unwrap()
+    }
+
+    fn record(key: &str, value: &str) -> SessionRecord {
+        let mut record = SessionRecord::new();
+        record.data.insert(key.to_string(), Value::from(value));
+        record
+    }
+
+    /// 値の途中の1文字を変える
+    fn tamper(value: &str) -> String {
+        let mut chars: Vec<char> = value.chars().collect();
+        let i = chars.len() / 2;
+        chars[i] = if chars[i] == 'A' { 'B' } else { 'A' };
+        chars.into_iter().collect()
+    }
+
+    fn temp_dir(name: &str) -> String {
+        std::env::temp_dir().join(format!("session-test-{}-{}", name, std::process::id())).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn rejects_missing_or_short_keys() {
+        let config = |keys: Vec<String>| SessionConfig {
+            cookie_name: "session".to_string(),
+            keys,
+            store: SessionStoreKind::Cookie,
+            same_site: SameSite::Lax,
+            secure: true,
+            http_only: true,
+            idle_timeout: 1,
+            absolute_timeout: 1,
+        };
+        assert!(Sessions::new(&config(Vec::new())).is_err());
+        assert!(Sessions::new(&config(vec!["short".to_string()])).is_err());
+    }
+
+    #[test]
+    fn cookie_store_encrypts_data() {
+        let sessions = sessions(SessionStoreKind::Cookie, &[NEW_KEY]);
+        let cookie = sessions.save(None, &record("user", "alice")).unwrap();
+        assert!(!cookie.value().contains("alice"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+
+        let (id, loaded) = sessions.load(cookie.value()).unwrap();
+        assert!(id.is_none());
+        assert_eq!(loaded.data["user"], "alice");
+        assert!(sessions.load(&tamper(cookie.value())).is_none());
+    }
+
+    #[test]
+    fn memory_store_signs_id() {
+        let sessions = sessions(SessionStoreKind::Memory, &[NEW_KEY]);
+        let cookie = sessions.save(None, &record("user", "alice")).unwrap();
+        assert!(!cookie.value().contains("alice"));
+
+        let (id, loaded) = sessions.load(cookie.value()).unwrap();
+        assert!(cookie.value().ends_with(id.as_deref().unwrap()));
+        assert_eq!(loaded.data["user"], "alice");
+        assert!(sessions.load(&tamper(cookie.value())).is_none());
+        // 署名のないIDは受け付けない
+        assert!(sessions.load(&id.unwrap()).is_none());
+    }
+
+    #[test]
+    fn old_keys_only_verify() {
+        let old = sessions(SessionStoreKind::Cookie, &[OLD_KEY]);
+        let rotated = sessions(SessionStoreKind::Cookie, &[NEW_KEY, OLD_KEY]);
+        let removed = sessions(SessionStoreKind::Cookie, &[NEW_KEY]);
+
+        let old_cookie = old.save(None, &record("user", "alice")).unwrap();
+        assert_eq!(rotated.load(old_cookie.value()).unwrap().1.data["user"], "alice");
+        assert!(removed.load(old_cookie.value()).is_none());
+
+        // 新しいCookieは先頭の鍵で発行する
+        let new_cookie = rotated.save(None, &record("user", "bob")).unwrap();
+        assert_eq!(removed.load(new_cookie.value()).unwrap().1.data["user"], "bob");
+        assert!(old.load(new_cookie.value()).is_none());
+    }
+
+    #[test]
+    fn expired_sessions_are_not_loaded() {
+        let sessions = sessions(SessionStoreKind::Memory, &[NEW_KEY]);
+        let now = Utc::now().timestamp();
+
+        let mut idle = record("user", "alice");
+        idle.accessed = now - 3601;
+        let cookie = sessions.save(None, &idle).unwrap();
+        assert!(sessions.load(cookie.value()).is_none());
+
+        let mut old = record("user", "alice");
+        old.created = now - 86401;
+        let cookie = sessions.save(None, &old).unwrap();
+        assert!(sessions.load(cookie.value()).is_none());
+
+        let cookie = sessions.save(None, &record("user", "alice")).unwrap();
+        assert!(sessions.load(cookie.value()).is_some());
+    }
+
+    #[test]
+    fn file_store_saves_json_per_session() {
+        let dir = temp_dir("file");
+        let sessions = sessions(SessionStoreKind::File(dir.clone()), &[NEW_KEY]);
+        let cookie = sessions.save(None, &record("user", "alice")).unwrap();
+        let (id, _) = sessions.load(cookie.value()).unwrap();
+        let path = PathBuf::from(&dir).join(format!("{}.json", id.as_deref().unwrap()));
+        let saved = fs::read_to_string(&path);
+
+        // 再起動後 (別のインスタンス) でも読める
+        let restarted = self::sessions(SessionStoreKind::File(dir.clone()), &[NEW_KEY, OLD_KEY]);
+        let reloaded = restarted.load(cookie.value()).map(|(_, r)| r.data["user"].clone());
+
+        // 期限切れのセッションはファイルも消す
+        let mut expired = record("user", "alice");
+        expired.accessed -= 3601;
+        let expired_cookie = sessions.save(id.clone(), &expired).unwrap();
+        let expired_loaded = sessions.load(expired_cookie.value()).is_some();
+        let file_removed = !path.exists();
+
+        let store = FileStore { dir: PathBuf::from(&dir) };
+        let traversal = store.path("../x").is_none();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(saved.unwrap().contains("\"alice\""));
+        assert_eq!(reloaded, Some(Value::from("alice")));
+        assert!(!expired_loaded);
+        assert!(file_removed);
+        assert!(traversal);
+    }
+}
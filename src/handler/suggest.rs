@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+
+use strsim::normalized_levenshtein;
+
+use super::path::encode_path;
+
+// スキャナーがよく探すパスの断片。これらを含むパスには候補を出さない
+const PROBE_PATTERNS: &[&str] = &[
+    ".env", ".git", ".svn", ".htaccess", ".htpasswd", ".php", ".asp", ".jsp", ".cgi", "cgi-bin",
+    "wp-", "wordpress", "phpmyadmin", "xmlrpc", "admin.", "config.", "backup",
+    "/etc/", "passwd", "..", "%2e", "shell", "eval(",
+];
+
+// 404ごとの計算量を抑える。これより長いパスには候補を出さない
+const MAX_PATH_LEN: usize = 256;
+// 比較するパスの上限。浅い階層のパスから残す
+const MAX_CANDIDATES: usize = 5000;
+
+/// 404ページの「もしかして」候補を計算する
+#[derive(Default)]
+pub struct PathSuggester {
+    // (URLパス, 比較用の小文字パス)
+    paths: Vec<(String, String)>,
+    max_results: usize,
+    threshold: f64,
+}
+
+impl PathSuggester {
+    /// keysはstatic_cacheのキー。max_resultsが0なら無効
+    pub fn new<'a>(keys: impl Iterator<Item = &'a String>, max_results: usize, threshold: f64) -> Self {
+        let mut seen = HashSet::new();
+        let mut paths = keys
+            .filter(|key| !key.split('/').any(|s| s.starts_with('.')))
+            .map(|key| (encode_path(key.strip_suffix("index.html").unwrap_or(key)), format!("/{}", key.to_lowercase())))
+            .filter(|(url, _)| seen.insert(url.clone()))
+            .collect::<Vec<_>>();
+        if paths.len() > MAX_CANDIDATES {
+            paths.sort_by(|a, b| (a.0.matches('/').count(), &a.0).cmp(&(b.0.matches('/').count(), &b.0)));
+            paths.truncate(MAX_CANDIDATES);
+            println!("Path suggestions are limited to {} paths", MAX_CANDIDATES);
+        }
+        PathSuggester { paths, max_results, threshold }
+    }
+
+    /// pathは "/" から始まるデコード済みのパス。似ている順にURLパスを返す
+    pub fn suggest(&self, path: &str) -> Vec<String> {
+        if self.max_results == 0 || path.len() > MAX_PATH_LEN || looks_like_probe(path) {
+            return Vec::new();
+        }
+
+        let path = path.to_lowercase();
+        let mut scored: Vec<(f64, &String)> = self.paths.iter()
+            .map(|(url, candidate)| (similarity(&path, candidate), url))
+            .filter(|(score, _)| *score >= self.threshold)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter()
+            .take(self.max_results)
+            .map(|(_, url)| url.clone())
+            .collect()
+    }
+}
+
+/// 編集距離とトークンの一致度の平均 (0.0 - 1.0)
+fn similarity(a: &str, b: &str) -> f64 {
+    let tokens = |s: &str| -> HashSet<String> {
+        s.split(['/', '.', '-', '_', ' '])
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string())
+            .collect()
+    };
+    let (ta, tb) = (tokens(a), tokens(b));
+    let union = ta.union(&tb).count();
+    let jaccard = if union == 0 { 0.0 } else { ta.intersection(&tb).count() as f64 / union as f64 };
+
+    (normalized_levenshtein(a, b) + jaccard) / 2.0
+}
+
+pub fn looks_like_probe(path: &str) -> bool {
+    let path = path.to_lowercase();
+    PROBE_PATTERNS.iter().any(|p| path.contains(p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggester(keys: &[String]) -> PathSuggester {
+        PathSuggester::new(keys.iter(), 3, 0.5)
+    }
+
+    #[test]
+    fn suggests_similar_paths() {
+        let keys = ["about.html", "blog/index.html", ".well-known/x.txt"].map(String::from);
+        let suggester = suggester(&keys);
+        assert_eq!(suggester.suggest("/abuot.html"), vec!["/about.html"]);
+        assert_eq!(suggester.suggest("/blog/indx.html"), vec!["/blog/"]);
+        assert!(suggester.suggest("/.well-known/x.txt").is_empty());
+        assert!(suggester.suggest("/about.php").is_empty());
+    }
+
+    #[test]
+    fn long_paths_are_not_compared() {
+        let keys = [format!("{}.html", "a".repeat(200))];
+        let suggester = suggester(&keys);
+        assert_eq!(suggester.suggest(&format!("/{}.htm", "a".repeat(200))).len(), 1);
+        assert!(suggester.suggest(&format!("/{}.htm", "a".repeat(MAX_PATH_LEN))).is_empty());
+    }
+
+    #[test]
+    fn candidates_are_capped() {
+        let mut keys: Vec<String> = (0..MAX_CANDIDATES).map(|i| format!("deep/dir/page{}.html", i)).collect();
+        keys.push("top.html".to_string());
+        let suggester = suggester(&keys);
+        assert_eq!(suggester.paths.len(), MAX_CANDIDATES);
+        // 浅い階層のパスが残る
+        assert_eq!(suggester.suggest("/tpo.html"), vec!["/top.html"]);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde_json::Value;
+use sha2::{Digest, Sha256, Sha384};
+use tera::{Function, Tera};
+
+use super::csrf::template_token;
+use super::path::encode_path;
+use crate::sys::init::AppConfig;
+
+// include_staticで埋め込めるファイルの最大サイズ
+const INCLUDE_STATIC_LIMIT: usize = 64 * 1024;
+
+/// テンプレート用の関数とフィルターを登録する
+///
+/// - `asset_url(path)` 内容のハッシュを付けたURL ("/css/app.css?v=1a2b3c4d")
+/// - `sri(path)` integrity属性に使うハッシュ ("sha384-...")
+/// - `t(key, lang)` _data/i18n/<lang> の翻訳 (keyは "nav.home" のようにドット区切り)
+/// - `now_tz(timezone)` 現在時刻 (RFC 3339。Tera組み込みのnowとdateはそのまま使える)
+/// - `date_tz(format, timezone)` 日時 (RFC 3339の文字列かUNIX時間) の整形
+/// - `include_static(path)` SVGなど小さなファイルをそのまま埋め込む
+/// - `csrf_token()` フォームに入れるCSRFトークン
+pub fn register(tera: &mut Tera, static_cache: &HashMap<String, Bytes>, data: &Value, app_config: &AppConfig) {
+    let mut hashes = HashMap::new();
+    for (key, content) in static_cache {
+        if key.ends_with(".html") {
+            continue;
+        }
+        let short = hex::encode(&Sha256::digest(content)[..4]);
+        let sri = format!("sha384-{}", STANDARD.encode(Sha384::digest(content)));
+        hashes.insert(key.clone(), (short, sri));
+    }
+    let hashes = Arc::new(hashes);
+
+    let asset_hashes = hashes.clone();
+    tera.register_function("asset_url", move |args: &HashMap<String, Value>| {
+        let path = path_arg(args)?;
+        let (short, _) = asset_hashes.get(&path).ok_or_else(|| not_found("asset_url", &path))?;
+        Ok(Value::String(format!("{}?v={}", encode_path(&path), short)))
+    });
+
+    tera.register_function("sri", move |args: &HashMap<String, Value>| {
+        let path = path_arg(args)?;
+        let (_, sri) = hashes.get(&path).ok_or_else(|| not_found("sri", &path))?;
+        Ok(Value::String(sri.clone()))
+    });
+
+    let translations = data.get("i18n").cloned().unwrap_or(Value::Null);
+    let default_locale = app_config.default_locale.clone();
+    tera.register_function("t", move |args: &HashMap<String, Value>| {
+        let key = args.get("key").and_then(|v| v.as_str())
+            .ok_or_else(|| tera::Error::msg("t: missing `key` argument"))?;
+        let lang = args.get("lang").and_then(|v| v.as_str()).unwrap_or(&default_locale);
+        let lookup = |lang: &str| key.split('.').try_fold(translations.get(lang)?, |value, segment| value.get(segment));
+        let found = lookup(lang).or_else(|| lookup(&default_locale));
+        // 翻訳がなければキーをそのまま返す
+        Ok(found.cloned().unwrap_or_else(|| Value::String(key.to_string())))
+    });
+
+    let timezone = app_config.timezone;
+    tera.register_function("now_tz", move |args: &HashMap<String, Value>| {
+        let tz = tz_arg(args, timezone)?;
+        Ok(Value::String(Utc::now().with_timezone(&tz).to_rfc3339()))
+    });
+
+    tera.register_filter("date_tz", move |value: &Value, args: &HashMap<String, Value>| {
+        let tz = tz_arg(args, timezone)?;
+        let format = args.get("format").and_then(|v| v.as_str()).unwrap_or("%Y-%m-%d");
+        let time: DateTime<Utc> = match value {
+            Value::Number(n) => n.as_i64().and_then(|ts| Utc.timestamp_opt(ts, 0).single()),
+            Value::String(s) => DateTime::parse_from_rfc3339(s).ok().map(|t| t.with_timezone(&Utc)),
+            _ => None,
+        }
+        .ok_or_else(|| tera::Error::msg(format!("date_tz: cannot parse {}", value)))?;
+        Ok(Value::String(time.with_timezone(&tz).format(format).to_string()))
+    });
+
+    tera.register_function("include_static", IncludeStatic { static_cache: static_cache.clone() });
+
+    // キャッシュするページではプレースホルダーを返し、描画後にリクエストのトークンへ置き換える
+    tera.register_function("csrf_token", |_: &HashMap<String, Value>| Ok(Value::String(template_token())));
+
+    // ユーザー定義の関数やフィルター
+    for hook in &app_config.template_hooks {
+        hook(tera);
+    }
+}
+
+/// 出力をエスケープしないのでFunctionを直接実装する
+struct IncludeStatic {
+    static_cache: HashMap<String, Bytes>,
+}
+
+impl Function for IncludeStatic {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let path = path_arg(args)?;
+        let content = self.static_cache.get(&path).ok_or_else(|| not_found("include_static", &path))?;
+        if content.len() > INCLUDE_STATIC_LIMIT {
+            return Err(tera::Error::msg(format!("include_static: {} is too large", path)));
+        }
+        let text = std::str::from_utf8(content)
+            .map_err(|_| tera::Error::msg(format!("include_static: {} is not text", path)))?;
+        Ok(Value::String(text.to_string()))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+fn path_arg(args: &HashMap<String, Value>) -> tera::Result<String> {
+    args.get("path")
+        .and_then(|v| v.as_str())
+        .map(|p| p.trim_start_matches('/').to_string())
+        .ok_or_else(|| tera::Error::msg("missing `path` argument"))
+}
+
+fn tz_arg(args: &HashMap<String, Value>, default: Tz) -> tera::Result<Tz> {
+    match args.get("timezone").and_then(|v| v.as_str()) {
+        Some(name) => name.parse().map_err(|_| tera::Error::msg(format!("unknown timezone: {}", name))),
+        None => Ok(default),
+    }
+}
+
+fn not_found(function: &str, path: &str) -> tera::Error {
+    tera::Error::msg(format!("{}: {} not found in static files", function, path))
+}
@@ -1,58 +1,131 @@
-use actix_web::dev::ServiceResponse;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web::middleware::{ErrorHandlerResponse, Logger};
 use env_logger::Env;
-use sys::app_set;
 
+use crate::handler::client_info::ClientInfo;
+use crate::handler::{block_rules, cors, csrf, ip_access, jwt_auth, rate_limit, router, security_headers, session};
 use crate::sys::app_set::AppSet;
 use crate::sys::init::AppConfig;
+use crate::sys::{export, privilege, proxy_protocol};
 
 mod sys;
 mod handler;
 
-#[actix_web::get("/{path:.*}")]
+#[actix_web::route("/{path:.*}", method = "GET", method = "HEAD", method = "OPTIONS")]
 async fn index(app_set: web::Data<AppSet>, req: HttpRequest) -> impl Responder {
-    app_set.handler.handle_request(req).await
+    app_set.handle_request(req).await
 }
 
-#[actix_web::get("/err/{statuscode}")]
-pub async fn error_test(statuscode: web::Path<u16>) -> impl Responder {
-    let status_code = *statuscode;
-    HttpResponse::build(actix_web::http::StatusCode::from_u16(status_code)
-        .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR))
+/// GET/HEAD/OPTIONS以外のメソッド
+async fn method_not_allowed() -> HttpResponse {
+    HttpResponse::MethodNotAllowed()
+        .insert_header(("Allow", router::ALLOWED_METHODS))
         .finish()
 }
 
-
 fn err_handler<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>, actix_web::Error> {
     let app_set = res.request().app_data::<web::Data<AppSet>>().unwrap();
-    let response = app_set.err_handler.page_generate(&res);
-    return Ok(ErrorHandlerResponse::Response(res.into_response(response.map_into_right_body())));
+    if res.status() == actix_web::http::StatusCode::NOT_FOUND {
+        app_set.not_found_log.record(res.request());
+    }
+    let site = app_set.site_or_default(res.request());
+    let response = site.err_handler.page_generate(&res);
+    Ok(ErrorHandlerResponse::Response(res.into_response(response.map_into_right_body())))
+}
+
+fn build_app(
+    app_set: web::Data<AppSet>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    // %a は転送ヘッダーを無条件に信用するので、解決済みのクライアントIPを使う
+    let logger = Logger::new(r#"%{client_ip}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("client_ip", |req| ClientInfo::get(req.request()).ip.to_string());
+
+    App::new()
+        // JWTで認証したリクエストを除外するのでjwt_authの内側に置く
+        .wrap(middleware::from_fn(csrf::middleware))
+        .wrap(middleware::from_fn(jwt_auth::middleware))
+        .wrap(middleware::from_fn(session::middleware))
+        // 429もログに残し、ErrHandlerで描画するのでこの位置に置く
+        .wrap(middleware::from_fn(rate_limit::middleware))
+        // 拒否したクライアントはレート制限のバケットを使わないようにrate_limitの外側に置く
+        .wrap(middleware::from_fn(ip_access::middleware))
+        .wrap(logger)
+        .wrap(middleware::ErrorHandlers::new().default_handler(err_handler))
+        // プリフライトをRouterより前に処理し、エラーページにもCORSヘッダーを付ける
+        .wrap(middleware::from_fn(cors::middleware))
+        // エラーページにもnonceとヘッダーが付くようにErrorHandlersの外側に置く
+        .wrap(middleware::from_fn(security_headers::middleware))
+        // 遮断するリクエストはエラーページの描画もログもしないので圧縮以外の最も外側に置く
+        .wrap(middleware::from_fn(block_rules::middleware))
+        // ErrHandlerが差し替えたボディも圧縮するように最も外側に置く
+        .wrap(middleware::Compress::default())
+        .app_data(app_set)
+        .service(handler::admin::scope())
+        // index より前に登録しないと /err/ が index に一致してしまう
+        .service(handler::diagnostics::error_preview)
+        .service(index)
+        .default_service(web::to(method_not_allowed))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
-    let app_config = AppConfig::new();
+    let app_config = AppConfig::new().resolve_data_files();
 
     let app_set_instance = AppSet::new(app_config.clone()).await;
 
+    // export <出力先> [--gzip] : サーバーを起動せずサイトを静的ファイルとして書き出す
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("export") {
+        let out_dir = args.iter().skip(2).find(|a| !a.starts_with("--")).map(|a| a.as_str()).unwrap_or("dist");
+        let gzip = args.iter().any(|a| a == "--gzip");
+        return export::export(&app_set_instance, std::path::Path::new(out_dir), gzip).await;
+    }
+
     let app_set = web::Data::new(app_set_instance);
+
+    // 404の集計を定期的にファイルへ書き出す
+    if let Some(dump_path) = app_config.not_found_dump_path.clone() {
+        let app_set = app_set.clone();
+        let interval = std::time::Duration::from_secs(app_config.not_found_dump_interval.max(1));
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(err) = app_set.not_found_log.dump(&dump_path) {
+                    eprintln!("Failed to dump 404 log: {}", err);
+                }
+            }
+        });
+    }
     
-    let server = HttpServer::new(move || {
-        App::new()
-            .wrap(Logger::default())
-            .wrap(middleware::ErrorHandlers::new().default_handler(err_handler))
-            .app_data(app_set.clone())
-            .service(index)
-            .service(error_test)
-    })
-    .bind(app_config.server_bind.clone())?
-    .workers(app_config.server_workers.clone())
-    .backlog(app_config.server_backlog.clone())
-    .run();
-    
+    let server = if app_config.proxy_protocol {
+        let builder = proxy_protocol::bind(&app_config, move || build_app(app_set.clone()))?;
+        // ソケットのバインドとファイルの読み込みが終わったので権限を落とす
+        privilege::drop_privileges(&app_config)?;
+        builder.run()
+    } else {
+        let builder = HttpServer::new(move || build_app(app_set.clone()))
+            .on_connect(block_rules::on_connect)
+            .workers(app_config.server_workers)
+            .backlog(app_config.server_backlog)
+            .bind(app_config.server_bind.clone())?;
+        privilege::drop_privileges(&app_config)?;
+        builder.run()
+    };
+
     server.await?;
 
     Ok(())
@@ -1,63 +1,136 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse};
 
 use super::init::AppConfig;
-use crate::handler::{err_page::ErrHandler, router::Router};
-use bytes::Bytes;
-use tera::Tera;
+use super::site::Site;
+use crate::handler::basic_auth::BasicAuth;
+use crate::handler::block_rules::BlockRules;
+use crate::handler::cors::Cors;
+use crate::handler::ip_access::IpAccess;
+use crate::handler::jwt_auth::JwtAuth;
+use crate::handler::not_found_log::NotFoundLog;
+use crate::handler::path::normalize_path;
+use crate::handler::rate_limit::RateLimiter;
+use crate::handler::rewrite::RewriteRules;
+use crate::handler::security_headers::SecurityHeaders;
+use crate::handler::session::Sessions;
 
 pub struct AppSet {
     pub app_config: AppConfig,
-    pub err_handler: ErrHandler,
-    pub handler: Router,
-    pub template: Tera,
-    pub static_cache: HashMap<String, Bytes>,
+    pub default_site: Site,
+    pub sites: Vec<Site>,
+    pub not_found_log: NotFoundLog,
+    pub security_headers: SecurityHeaders,
+    pub rate_limiter: Option<RateLimiter>,
+    pub jwt_auth: Option<JwtAuth>,
+    pub sessions: Option<Sessions>,
+    pub cors: Option<Cors>,
+    pub block_rules: Option<BlockRules>,
+    pub ip_access: Option<IpAccess>,
+    pub rewrite_rules: Arc<RewriteRules>,
 }
 
 impl AppSet {
-    pub async  fn new(app_config: AppConfig) -> Self {
-        let static_cache = AppSet::load_cache_static_files(Path::new(&app_config.data_path));
-        let template = AppSet::load_template_html(&static_cache);
-
-        let app_set = AppSet {
-            app_config: app_config.clone(),
-            err_handler: ErrHandler::new(template.clone()).await,
-            handler: Router::new(&app_config, template.clone(), static_cache.clone()),
-            template,
-            static_cache,
+    pub async fn new(app_config: AppConfig) -> Self {
+        if let Some(status) = app_config.unknown_host_status.filter(|s| !(400..500).contains(s)) {
+            panic!("unknown_host_status must be a 4xx status: {}", status);
+        }
+        let rewrite_rules = match &app_config.rewrite_rules_path {
+            Some(path) => RewriteRules::load(path).unwrap_or_else(|err| panic!("{}", err)),
+            None => RewriteRules::default(),
         };
-        app_set
+        let rewrite_rules = Arc::new(rewrite_rules);
+        let basic_auth = Arc::new(BasicAuth::new(&app_config.basic_auth, &app_config.basic_auth_failure_limit));
+
+        let default_site = Site::new(&app_config, app_config.default_site(), rewrite_rules.clone(), basic_auth.clone()).await;
+        let mut sites = Vec::new();
+        for site_config in &app_config.sites {
+            sites.push(Site::new(&app_config, site_config.clone(), rewrite_rules.clone(), basic_auth.clone()).await);
+        }
+
+        AppSet {
+            not_found_log: NotFoundLog::new(app_config.not_found_log_size),
+            security_headers: SecurityHeaders::new(&app_config),
+            rate_limiter: app_config.rate_limit.as_ref().map(RateLimiter::new),
+            jwt_auth: app_config.jwt_auth.as_ref()
+                .map(|config| JwtAuth::new(config).unwrap_or_else(|err| panic!("{}", err))),
+            sessions: app_config.session.as_ref()
+                .map(|config| Sessions::new(config).unwrap_or_else(|err| panic!("{}", err))),
+            block_rules: (!app_config.block_rules.is_empty())
+                .then(|| BlockRules::new(&app_config.block_rules, app_config.block_tarpit_max).unwrap_or_else(|err| panic!("{}", err))),
+            ip_access: IpAccess::is_enabled(&app_config).then(|| IpAccess::new(&app_config)),
+            cors: (!app_config.cors.is_empty())
+                .then(|| Cors::new(&app_config.cors, app_config.unicode_nfc).unwrap_or_else(|err| panic!("{}", err))),
+            rewrite_rules,
+            app_config,
+            default_site,
+            sites,
+        }
+    }
+
+    /// Hostヘッダーから配信するサイトを選ぶ
+    /// 一致しない場合、unknown_host_statusが設定されていればNoneを返す
+    pub fn site_for(&self, req: &HttpRequest) -> Option<&Site> {
+        let host = request_host(req);
+        if let Some(site) = self.sites.iter().find(|site| site.matches_host(&host)) {
+            return Some(site);
+        }
+        match self.app_config.unknown_host_status {
+            Some(_) => None,
+            None => Some(&self.default_site),
+        }
     }
 
-    fn load_cache_static_files(dir: &Path) -> HashMap<String, Bytes> {
-        let mut cache = HashMap::new();
-        
-        if let Ok(entries) = fs::read_dir(dir) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if path.is_file() {
-                        if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                            if let Ok(content) = fs::read(&path) {
-                                cache.insert(filename.to_string(), Bytes::from(content));
-                            }
-                        }
-                    }
-                }
+    /// Hostに対応するサイトでリクエストを処理する
+    pub async fn handle_request(&self, req: HttpRequest) -> HttpResponse {
+        match self.site_for(&req) {
+            Some(site) => site.handler.handle_request(req).await,
+            None => {
+                let status = self.app_config.unknown_host_status.unwrap_or(421);
+                HttpResponse::build(StatusCode::from_u16(status).unwrap_or(StatusCode::MISDIRECTED_REQUEST))
+                    .finish()
             }
         }
+    }
 
-        cache
+    /// Routerより前に認証やアクセス制限を判定するパス (正規化済み、先頭 "/" なし)
+    /// リライトされる場合はリクエストのパスとリライト後のパスの両方を返す (正規化できなければ空)
+    pub fn request_paths(&self, req: &HttpRequest) -> Vec<String> {
+        let nfc = self.app_config.unicode_nfc;
+        let Ok(path) = normalize_path(req.path(), nfc) else {
+            return Vec::new();
+        };
+        let rewritten = self.rewrite_rules.rewritten_path(req, &path, nfc);
+        let mut paths = vec![path];
+        if let Some(rewritten) = rewritten.filter(|r| *r != paths[0]) {
+            paths.push(rewritten);
+        }
+        paths
     }
 
-    fn load_template_html(static_cach: &HashMap<String, Bytes>) -> Tera {
-        let mut tera = Tera::default();
-        for (filename, content) in static_cach {
-            if filename.ends_with(".html") {
-                if let Ok(template_content) = std::str::from_utf8(&content) {
-                    tera.add_raw_template(filename, template_content).expect("Failed to add template");
-                }
-            }
+    /// 全サイトのテンプレート描画キャッシュを捨てる
+    pub fn clear_render_cache(&self) {
+        for site in std::iter::once(&self.default_site).chain(&self.sites) {
+            site.handler.render_cache.clear();
         }
-        tera
     }
+
+    /// エラーページ用のサイト (Hostが不明な場合もデフォルトサイトで描画する)
+    pub fn site_or_default(&self, req: &HttpRequest) -> &Site {
+        self.site_for(req).unwrap_or(&self.default_site)
+    }
+}
+
+/// ポートを除いた小文字のHost (X-Forwarded-Hostは信用しない)
+pub fn request_host(req: &HttpRequest) -> String {
+    let host = req.headers().get("Host")
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().host())
+        .unwrap_or("");
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(""),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
 }
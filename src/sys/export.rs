@@ -0,0 +1,164 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use actix_web::{body, http::StatusCode, test::TestRequest, HttpResponse};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+
+use super::app_set::AppSet;
+use super::site::Site;
+use crate::handler::client_info::{ClientInfo, CLIENT_IP_PLACEHOLDER, CLIENT_SCHEME_PLACEHOLDER};
+use crate::handler::csrf::CSRF_PLACEHOLDER;
+use crate::handler::err_page::{DebugLevel, ErrPagePreview};
+use crate::handler::security_headers::NONCE_PLACEHOLDER;
+
+/// サイト全体を静的ファイルとして書き出す (CDNへのデプロイ用)
+///
+/// - HTMLテンプレートは描画結果、.mdは同じ名前の .html として書き出す
+/// - 一覧表示が有効なディレクトリは index.html として書き出す
+/// - エラーページは 404.html のようにステータスごとに書き出す
+/// - gzipがtrueならテキスト系のファイルに .gz を並べて置く
+///
+/// デフォルトサイトはout_dir直下、バーチャルホストは out_dir/<サイト名>/ に書き出す
+pub async fn export(app_set: &AppSet, out_dir: &Path, gzip: bool) -> io::Result<()> {
+    export_site(&app_set.default_site, out_dir, gzip).await?;
+    for site in &app_set.sites {
+        export_site(site, &out_dir.join(&site.site_config.name), gzip).await?;
+    }
+    Ok(())
+}
+
+async fn export_site(site: &Site, out_dir: &Path, gzip: bool) -> io::Result<()> {
+    let router = &site.handler;
+    let content = &router.content;
+    let mut count = 0;
+
+    for (key, bytes) in &content.static_cache {
+        // Basic認証で保護したファイルは公開先に出さない
+        if router.basic_auth.is_protected(key) {
+            continue;
+        }
+        if let Some(html) = content.rendered.get(key) {
+            let html_key = format!("{}.html", key.trim_end_matches(".md"));
+            if content.static_cache.contains_key(&html_key) {
+                eprintln!("Export skipped: {} conflicts with {}", key, html_key);
+                continue;
+            }
+            // 静的配信ではnonce、CSRFトークン、クライアント情報を使えないので空にする
+            let html = strip_placeholders(&String::from_utf8_lossy(html).replace(NONCE_PLACEHOLDER, ""));
+            write_file(out_dir, &html_key, &Bytes::from(html), gzip)?;
+        } else if key.ends_with(".html") {
+            match router.render_page(key, "", &ClientInfo::placeholder_context(), None, None) {
+                Ok(html) => write_file(out_dir, key, &Bytes::from(strip_placeholders(&html)), gzip)?,
+                Err(err) => {
+                    eprintln!("Template rendering error: {}: {}", key, err);
+                    continue;
+                }
+            }
+        } else {
+            write_file(out_dir, key, bytes, gzip)?;
+        }
+        count += 1;
+    }
+
+    // キーに現れるディレクトリのうち、index.htmlがなく一覧表示が有効なもの
+    let mut dirs = BTreeSet::from([String::new()]);
+    for key in content.static_cache.keys() {
+        let mut end = 0;
+        while let Some(pos) = key[end..].find('/') {
+            end += pos + 1;
+            dirs.insert(key[..end].to_string());
+        }
+    }
+    for dir in dirs {
+        let index = format!("{}index.html", dir);
+        if content.static_cache.contains_key(&index) || !router.autoindex.enabled(&dir) || router.basic_auth.is_protected(&dir) {
+            continue;
+        }
+        let req = TestRequest::get().uri(&format!("/{}", dir)).to_http_request();
+        let response = router.autoindex.render(&req, &dir, &content.static_cache, &content.modified, &content.template, &router.basic_auth);
+        if response.status() == StatusCode::OK {
+            write_file(out_dir, &index, &response_body(response).await?, gzip)?;
+            count += 1;
+        }
+    }
+
+    for &status in site.err_handler.status_message.keys() {
+        let Ok(status) = StatusCode::from_u16(status) else { continue };
+        // 書き出したときのホストや時刻などのデバッグ情報は出さない
+        let mut response = HttpResponse::build(status).finish();
+        response.extensions_mut().insert(ErrPagePreview { lang: None, accept: None, debug: DebugLevel::None });
+        let res = TestRequest::default().to_srv_response(response);
+        let response = site.err_handler.page_generate(&res);
+        write_file(out_dir, &format!("{}.html", status.as_u16()), &response_body(response).await?, gzip)?;
+        count += 1;
+    }
+
+    println!("Site exported: {} -> {} ({} files)", site.site_config.name, out_dir.display(), count);
+    Ok(())
+}
+
+/// リクエストごとに置き換えるプレースホルダーを取り除く
+fn strip_placeholders(html: &str) -> String {
+    [CSRF_PLACEHOLDER, CLIENT_IP_PLACEHOLDER, CLIENT_SCHEME_PLACEHOLDER].iter()
+        .fold(html.to_string(), |html, placeholder| html.replace(placeholder, ""))
+}
+
+async fn response_body(response: HttpResponse) -> io::Result<Bytes> {
+    body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| io::Error::other(err.to_string()))
+}
+
+fn write_file(out_dir: &Path, key: &str, content: &Bytes, gzip: bool) -> io::Result<()> {
+    let path = out_dir.join(key);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, content)?;
+
+    if gzip && is_compressible(key) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(content)?;
+        let compressed = encoder.finish()?;
+        // 小さくならなければ置かない
+        if compressed.len() < content.len() {
+            fs::write(out_dir.join(format!("{}.gz", key)), compressed)?;
+        }
+    }
+    Ok(())
+}
+
+/// 画像やアーカイブなど圧縮済みの形式は対象外
+fn is_compressible(key: &str) -> bool {
+    let mime = mime_guess::from_path(key).first_or_octet_stream();
+    mime.type_() == "text"
+        || matches!(mime.subtype().as_str(), "javascript" | "json" | "xml" | "svg")
+        || mime.suffix().is_some_and(|s| s == "xml" || s == "json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::init::AppConfig;
+
+    #[actix_web::test]
+    async fn error_pages_have_no_debug_info() {
+        let app_set = AppSet::new(AppConfig::new()).await;
+        let out_dir = std::env::temp_dir().join(format!("export-test-{}", std::process::id()));
+        export(&app_set, &out_dir, false).await.unwrap();
+
+        let html = fs::read_to_string(out_dir.join("404.html"));
+        let index_exists = out_dir.join("index.html").exists();
+        fs::remove_dir_all(&out_dir).unwrap();
+
+        let html = html.unwrap();
+        assert!(index_exists);
+        assert!(html.contains("404"));
+        for debug in ["<p>Debug</p>", "Last-Time", "Client-Ip", "0.0.0.0", "Unknown"] {
+            assert!(!html.contains(debug), "{}", debug);
+        }
+    }
+}
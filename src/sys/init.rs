@@ -1,3 +1,9 @@
+use std::collections::HashMap;
+
+use chrono_tz::Tz;
+use ipnet::IpNet;
+use actix_web::cookie::SameSite;
+use tera::Tera;
 
 #[derive(Clone)]
 pub struct AppConfig {
@@ -5,16 +11,488 @@ pub struct AppConfig {
     pub server_backlog: u32,
     pub server_workers: usize,
     pub data_path: String,
+    // err_template.htmlなどを置くディレクトリ
+    pub template_path: String,
+    // 静的ファイルに付けるCache-Control (None なら付けない)
+    pub cache_control: Option<String>,
+    // Hostヘッダーごとに別のデータディレクトリを返すバーチャルホスト
+    pub sites: Vec<SiteConfig>,
+    // どのサイトにも一致しないHostへのステータス (None ならデフォルトサイトを返す。4xxのみ。421か404を想定)
+    pub unknown_host_status: Option<u16>,
+    // 拡張子なしのURLを .html や /index.html に解決するモード (デフォルトサイト。sitesはSiteConfigで指定する)
+    pub clean_urls: CleanUrls,
+    // SPAのエントリーHTML (設定すると拡張子なしの存在しないパスにこれを返す。デフォルトサイト)
+    pub spa_fallback: Option<String>,
+    // index.htmlのないディレクトリを一覧表示するディレクトリ (デフォルトサイト)
+    pub autoindex: Vec<AutoIndexConfig>,
+    // .mdファイルを埋め込むレイアウトテンプレート (template_pathのファイル名)
+    pub markdown_layout: String,
+    // テンプレートの t() で lang を省略したときの言語
+    pub default_locale: String,
+    // テンプレートの now_tz() と date_tz で timezone を省略したときのタイムゾーン
+    pub timezone: Tz,
+    // 独自のテンプレート関数やフィルターを登録する関数
+    pub template_hooks: Vec<fn(&mut Tera)>,
+    // 描画結果をキャッシュするテンプレートのglobパターン (フロントマターの cache でも指定できる)
+    pub render_cache: Vec<String>,
+    // 描画結果のキャッシュの有効期間(秒)
+    pub render_cache_ttl: u64,
+    // 404ページに出す「もしかして」候補の最大数 (0なら無効)
+    pub suggest_max_results: usize,
+    // 候補にする類似度の下限 (0.0 - 1.0)
+    pub suggest_threshold: f64,
+    // 404の集計に保持する最大件数 (0なら集計しない)
+    pub not_found_log_size: usize,
+    // 404の集計をCSVで定期的に書き出すファイルと間隔(秒) (相対パスはdata_pathから)
+    pub not_found_dump_path: Option<String>,
+    pub not_found_dump_interval: u64,
+    // /_admin/ 以下にアクセスできるネットワーク
+    pub admin_networks: Vec<IpNet>,
+    // リダイレクト/リライトルールのTOMLファイル
+    pub rewrite_rules_path: Option<String>,
+    // trueならリクエストパスとキャッシュのキーをUnicode NFCに正規化する
+    pub unicode_nfc: bool,
+    // バインド後に切り替えるユーザー/グループ (None なら切り替えない)
+    pub run_user: Option<String>,
+    pub run_group: Option<String>,
+    // trueならdata_pathへchrootする
+    pub chroot: bool,
+    // ここに含まれるピアからのみX-Forwarded-For等のヘッダーやPROXYプロトコルを信用する
+    pub trusted_proxies: Vec<IpNet>,
+    // trueならPROXYプロトコル(v1/v2)ヘッダーを受け付ける
+    pub proxy_protocol: bool,
+    // すべてのレスポンスに付けるセキュリティヘッダー ({nonce} はリクエストごとのCSP nonceに置き換える)
+    pub security_headers: Vec<(String, String)>,
+    // パスごとのセキュリティヘッダーの上書き (後のものが優先)
+    pub security_header_overrides: Vec<SecurityHeaderOverride>,
+    // クライアントIPごとのレート制限 (None なら制限しない)
+    pub rate_limit: Option<RateLimitConfig>,
+    // パスのプレフィックスごとのBasic認証
+    pub basic_auth: Vec<BasicAuthConfig>,
+    // Basic認証の失敗回数の制限 (超えたら429)
+    pub basic_auth_failure_limit: RateLimitConfig,
+    // Bearerトークン(JWT)で保護するルート (None なら検証しない)
+    pub jwt_auth: Option<JwtAuthConfig>,
+    // Cookieによるセッション (None なら無効)
+    pub session: Option<SessionConfig>,
+    // POSTなど安全でないメソッドのCSRF対策 (None なら検証しない)
+    pub csrf: Option<CsrfConfig>,
+    // パスのパターンごとのCORSポリシー (最初に一致したもの。空ならCORSヘッダーを付けない)
+    pub cors: Vec<CorsPolicy>,
+    // スキャナーや攻撃の探索リクエストを遮断するルール (最初に一致したもの)
+    pub block_rules: Vec<BlockRule>,
+    // 同時にtarpitで遅延させる接続の上限 (超えたら接続を切る)
+    pub block_tarpit_max: usize,
+    // 解決済みのクライアントIPによるアクセス制限 (すべてのパス)
+    pub ip_access: IpAccessList,
+    // パスのプレフィックスごとのアクセス制限 (最も長く一致したもの。全体の制限に加えて適用する)
+    pub ip_access_paths: Vec<IpAccessPath>,
+    // 開発モード (/err/{statuscode} などの診断用エンドポイントを誰でも使える)
+    pub dev_mode: bool,
+    // 開発モードでなくても診断用エンドポイントを使えるネットワーク
+    pub diagnostics_networks: Vec<IpNet>,
 }
 
 impl AppConfig {
     pub fn new() -> Self {
-        let app_config = AppConfig {
+        AppConfig {
             server_bind: "0.0.0.0:83".to_string(),
             server_backlog: 512,
             server_workers: 16,
             data_path: "data".to_string(),
-        };
-        app_config
+            template_path: "templates".to_string(),
+            cache_control: None,
+            sites: Vec::new(),
+            unknown_host_status: None,
+            clean_urls: CleanUrls::Off,
+            spa_fallback: None,
+            autoindex: Vec::new(),
+            markdown_layout: "markdown_layout.html".to_string(),
+            default_locale: "en".to_string(),
+            timezone: chrono_tz::UTC,
+            template_hooks: Vec::new(),
+            render_cache: Vec::new(),
+            render_cache_ttl: 300,
+            suggest_max_results: 3,
+            suggest_threshold: 0.5,
+            not_found_log_size: 10000,
+            not_found_dump_path: None,
+            not_found_dump_interval: 3600,
+            admin_networks: vec![
+                "127.0.0.0/8".parse().unwrap(),
+                "::1/128".parse().unwrap(),
+            ],
+            rewrite_rules_path: None,
+            unicode_nfc: false,
+            run_user: None,
+            run_group: None,
+            chroot: false,
+            trusted_proxies: vec![
+                "127.0.0.0/8".parse().unwrap(),
+                "::1/128".parse().unwrap(),
+            ],
+            proxy_protocol: false,
+            security_headers: vec![
+                (
+                    "Content-Security-Policy".to_string(),
+                    "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+                     style-src 'self' 'nonce-{nonce}' https://fonts.googleapis.com; \
+                     font-src 'self' https://fonts.gstatic.com; img-src 'self' data:; \
+                     object-src 'none'; base-uri 'self'; frame-ancestors 'none'".to_string(),
+                ),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+                ("Referrer-Policy".to_string(), "strict-origin-when-cross-origin".to_string()),
+                ("Permissions-Policy".to_string(), "camera=(), microphone=(), geolocation=()".to_string()),
+                ("X-Frame-Options".to_string(), "DENY".to_string()),
+                ("Cross-Origin-Opener-Policy".to_string(), "same-origin".to_string()),
+                ("Cross-Origin-Embedder-Policy".to_string(), "credentialless".to_string()),
+            ],
+            security_header_overrides: Vec::new(),
+            rate_limit: Some(RateLimitConfig {
+                burst: 200,
+                per_second: 50.0,
+                groups: Vec::new(),
+                max_clients: 100_000,
+            }),
+            basic_auth: Vec::new(),
+            basic_auth_failure_limit: RateLimitConfig {
+                burst: 10,
+                per_second: 1.0 / 60.0,
+                groups: Vec::new(),
+                max_clients: 10_000,
+            },
+            jwt_auth: None,
+            session: None,
+            csrf: Some(CsrfConfig {
+                cookie_name: "csrf_token".to_string(),
+                field_name: "csrf_token".to_string(),
+                header_name: "X-CSRF-Token".to_string(),
+                trusted_origins: Vec::new(),
+                secure: false,
+            }),
+            cors: Vec::new(),
+            block_rules: default_block_rules(),
+            block_tarpit_max: 256,
+            ip_access: IpAccessList::default(),
+            ip_access_paths: Vec::new(),
+            dev_mode: cfg!(debug_assertions),
+            diagnostics_networks: Vec::new(),
+        }
+    }
+
+    /// トップレベルの設定から作るデフォルトサイト
+    pub fn default_site(&self) -> SiteConfig {
+        SiteConfig {
+            name: "default".to_string(),
+            hosts: Vec::new(),
+            data_path: self.data_path.clone(),
+            template_path: self.template_path.clone(),
+            err_colors: HashMap::new(),
+            cache_control: self.cache_control.clone(),
+            clean_urls: self.clean_urls,
+            spa_fallback: self.spa_fallback.clone(),
+            autoindex: self.autoindex.clone(),
+        }
     }
-}
\ No newline at end of file
+
+    /// 設定されたファイルの相対パスをdata_pathからの絶対パスにする
+    /// 開くときはprivilege::current_pathを通すので、chrootした後も同じファイルを指す
+    pub fn resolve_data_files(mut self) -> Self {
+        let data_path = std::path::absolute(&self.data_path).expect("Failed to resolve data_path");
+        let resolve = |path: &mut String| *path = data_path.join(&*path).to_string_lossy().into_owned();
+
+        for config in &mut self.basic_auth {
+            resolve(&mut config.htpasswd);
+        }
+        if let Some(SessionConfig { store: SessionStoreKind::File(dir), .. }) = &mut self.session {
+            resolve(dir);
+        }
+        if let Some(path) = &mut self.not_found_dump_path {
+            resolve(path);
+        }
+        let ip_lists = std::iter::once(&mut self.ip_access).chain(self.ip_access_paths.iter_mut().map(|rule| &mut rule.list));
+        for file in ip_lists.filter_map(|list| list.file.as_mut()) {
+            resolve(file);
+        }
+        self
+    }
+
+    /// サーバーが読み書きするファイルとディレクトリ (resolve_data_filesで解決するもの)
+    /// data_pathの中にあっても静的ファイルとしては配信しない
+    pub fn private_files(&self) -> Vec<&String> {
+        let mut files: Vec<&String> = self.basic_auth.iter().map(|config| &config.htpasswd).collect();
+        if let Some(SessionConfig { store: SessionStoreKind::File(dir), .. }) = &self.session {
+            files.push(dir);
+        }
+        files.extend(&self.not_found_dump_path);
+        let ip_lists = std::iter::once(&self.ip_access).chain(self.ip_access_paths.iter().map(|rule| &rule.list));
+        files.extend(ip_lists.filter_map(|list| list.file.as_ref()));
+        files
+    }
+}
+
+#[derive(Clone)]
+pub struct SiteConfig {
+    pub name: String,
+    // "example.com" のような完全一致か "*.example.com" のようなワイルドカード
+    pub hosts: Vec<String>,
+    pub data_path: String,
+    pub template_path: String,
+    // エラーページの色 (ステータスコードの百の位 -> 色) を上書きする
+    pub err_colors: HashMap<u16, String>,
+    pub cache_control: Option<String>,
+    pub clean_urls: CleanUrls,
+    pub spa_fallback: Option<String>,
+    pub autoindex: Vec<AutoIndexConfig>,
+}
+
+#[derive(Clone)]
+pub struct AutoIndexConfig {
+    // data_pathからの相対ディレクトリ ("builds/" など。サブディレクトリも対象)
+    pub path: String,
+    // trueなら "." から始まるファイルも表示する
+    pub show_hidden: bool,
+    // 表示しないファイル名のglobパターン
+    pub exclude: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CleanUrls {
+    // 末尾が "/" のときだけindex.htmlを補う
+    Off,
+    // /about -> about.html (正規URLは /about、/about.html と /about/ はリダイレクト)
+    Html,
+    // /about -> about/index.html (正規URLは /about/、/about と /about/index.html はリダイレクト)
+    #[allow(dead_code)] // AppConfig::newで選択しなければ未使用
+    Directory,
+}
+
+#[derive(Clone)]
+pub struct SecurityHeaderOverride {
+    // リクエストパスのglobパターン ("/embed/*" など)
+    pub path: String,
+    // 値がNoneならそのヘッダーを付けない
+    pub headers: Vec<(String, Option<String>)>,
+}
+
+/// トークンバケットによるレート制限
+/// burst回まで連続で受け付け、1秒あたりper_second回分回復する
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    pub per_second: f64,
+    // パスごとに別のバケットと制限を使うグループ (最初に一致したもの)
+    pub groups: Vec<RateLimitGroup>,
+    // 保持するバケットの最大数 (クライアント数 x グループ数)
+    pub max_clients: usize,
+}
+
+#[derive(Clone)]
+pub struct RateLimitGroup {
+    pub name: String,
+    // リクエストパスのglobパターン ("/api/*" など)
+    pub path: String,
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Clone)]
+pub struct BasicAuthConfig {
+    // data_pathからの相対ディレクトリ ("staging/" など。サブディレクトリも対象)
+    pub path: String,
+    pub realm: String,
+    // htpasswdファイル (bcrypt, argon2, {SHA} のエントリに対応。更新すると再読み込みする)
+    // 相対パスはdata_pathから。chrootする場合はdata_pathの中に置く
+    pub htpasswd: String,
+}
+
+/// RS256, ES256, EdDSAの署名を検証する
+#[derive(Clone)]
+pub struct JwtAuthConfig {
+    // 検証に使う鍵 (JWKSファイルとPEMの公開鍵。両方指定してもよい)
+    pub jwks_path: Option<String>,
+    pub pem_path: Option<String>,
+    // 指定すれば iss / aud を検証する
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // exp / nbf の許容誤差(秒)
+    pub leeway: u64,
+    // 保護するルート (最初に一致したもの)
+    pub routes: Vec<JwtRoute>,
+}
+
+#[derive(Clone)]
+pub struct JwtRoute {
+    // リクエストパスのglobパターン ("/api/*" など)
+    pub path: String,
+    // 必要なスコープ (scope / scp クレーム)
+    pub scopes: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    // 署名/暗号化の鍵 (32文字以上)。先頭の鍵で発行し、残りは検証だけに使う
+    pub keys: Vec<String>,
+    pub store: SessionStoreKind,
+    pub same_site: SameSite,
+    pub secure: bool,
+    pub http_only: bool,
+    // 最後のアクセスからの有効期間と、作成からの最大の有効期間(秒)
+    pub idle_timeout: u64,
+    pub absolute_timeout: u64,
+}
+
+#[derive(Clone)]
+// 各種類はAppConfig::newで選択しなければ未使用
+pub enum SessionStoreKind {
+    // Cookieに内容を暗号化して入れる (4KB程度まで)
+    #[allow(dead_code)]
+    Cookie,
+    // サーバーのメモリに置く (再起動で消える)
+    #[allow(dead_code)]
+    Memory,
+    // ディレクトリにJSONファイルとして置く (相対パスはdata_pathから。chrootする場合はdata_pathの中に置く)
+    #[allow(dead_code)]
+    File(String),
+}
+
+/// double-submit cookieによるCSRF対策
+/// トークンはフォームのフィールドかヘッダーで送り、Cookieの値と一致させる
+#[derive(Clone)]
+pub struct CsrfConfig {
+    pub cookie_name: String,
+    // application/x-www-form-urlencoded のフォームのフィールド名
+    pub field_name: String,
+    // JavaScriptから送る場合のヘッダー名
+    pub header_name: String,
+    // リクエストのホスト以外に許可するOrigin ("https://example.com" など)
+    pub trusted_origins: Vec<String>,
+    pub secure: bool,
+}
+
+/// 別オリジンのフロントエンドからのfetchを許可する
+/// 資格情報付きのリクエストをフォームのPOSTなどで受ける場合はcsrfのtrusted_originsにも追加する
+#[derive(Clone)]
+pub struct CorsPolicy {
+    // リクエストパスのglobパターン ("/api/*" など)
+    pub path: String,
+    pub origins: Vec<CorsOrigin>,
+    // GET/HEAD/POST以外に許可するメソッド
+    pub methods: Vec<String>,
+    // 許可するリクエストヘッダー ("*" なら資格情報なしのリクエストのすべて)
+    pub headers: Vec<String>,
+    // ブラウザのJavaScriptから読めるようにするレスポンスヘッダー
+    pub expose_headers: Vec<String>,
+    // Cookieなどの資格情報付きのリクエストを許可する (CorsOrigin::Anyとは併用できない)
+    pub credentials: bool,
+    // プリフライトの結果をキャッシュさせる秒数
+    pub max_age: Option<u64>,
+}
+
+#[derive(Clone)]
+// 各種類はAppConfig::newで選択しなければ未使用
+pub enum CorsOrigin {
+    // すべてのオリジン (credentialsとは併用できない)
+    #[allow(dead_code)]
+    Any,
+    // "https://app.example.com"
+    #[allow(dead_code)]
+    Exact(String),
+    // "https://*.example.com" (example.com自身は含まない)
+    #[allow(dead_code)]
+    Subdomain(String),
+    // オリジン全体に一致させる正規表現 ("https://[a-z]+\\.example\\.com" など。^ と $ は付けなくてよい)
+    #[allow(dead_code)]
+    Regex(String),
+}
+
+/// 指定した条件がすべて一致したリクエストを遮断する (条件は1つ以上必要)
+#[derive(Clone)]
+pub struct BlockRule {
+    // /_admin/block-rules.json の集計に出す名前
+    pub name: String,
+    // 生のパスとパーセントデコードしたパスのどちらかに一致すれば条件を満たす
+    pub path: Option<BlockPath>,
+    // User-Agentの正規表現 (大文字小文字を区別しない)
+    pub user_agent: Option<String>,
+    // これらのメソッドなら条件を満たす (空なら条件にしない)
+    pub methods: Vec<String>,
+    // クエリ文字列がこの長さを超えたら条件を満たす
+    pub max_query_length: Option<usize>,
+    pub action: BlockAction,
+}
+
+#[derive(Clone)]
+pub enum BlockPath {
+    // "*.php" など (* は "/" も含めて一致する)
+    Glob(String),
+    Regex(String),
+}
+
+#[derive(Clone, Copy)]
+pub enum BlockAction {
+    // レスポンスを返さずに接続を切る
+    Drop,
+    // エラーページを描画せずに空の403を返す
+    Forbidden,
+    // 指定した秒数待たせてから403を返す
+    Tarpit(u64),
+}
+
+/// 組み込みのルール (block_rules に追加したり置き換えたりできる)
+pub fn default_block_rules() -> Vec<BlockRule> {
+    let rule = |name: &str, path: Option<BlockPath>, user_agent: Option<&str>, methods: &[&str], action| BlockRule {
+        name: name.to_string(),
+        path,
+        user_agent: user_agent.map(|ua| ua.to_string()),
+        methods: methods.iter().map(|m| m.to_string()).collect(),
+        max_query_length: None,
+        action,
+    };
+    vec![
+        rule(
+            "path-traversal",
+            Some(BlockPath::Regex(r"(?i)(\.\./|/\.\.$|%2e%2e|/etc/passwd|/proc/self/)".to_string())),
+            None, &[], BlockAction::Drop,
+        ),
+        rule(
+            "dotfiles",
+            Some(BlockPath::Regex(r"(?i)/\.(env|git|svn|hg|aws|ssh|htaccess|htpasswd|ds_store)(\.|/|$)".to_string())),
+            None, &[], BlockAction::Drop,
+        ),
+        rule(
+            "wordpress",
+            Some(BlockPath::Regex(r"(?i)/(wp-login\.php|wp-admin|wp-content|wp-includes|xmlrpc\.php)".to_string())),
+            None, &[], BlockAction::Tarpit(10),
+        ),
+        rule("php", Some(BlockPath::Glob("*.php".to_string())), None, &[], BlockAction::Forbidden),
+        rule(
+            "scanner-user-agents",
+            None,
+            Some(r"sqlmap|nikto|nmap|masscan|zgrab|nuclei|dirbuster|gobuster|wpscan|acunetix|netsparker"),
+            &[], BlockAction::Forbidden,
+        ),
+        rule("unusual-methods", None, None, &["TRACE", "TRACK", "CONNECT", "PROPFIND", "DEBUG"], BlockAction::Forbidden),
+        BlockRule { max_query_length: Some(2048), ..rule("long-query", None, None, &[], BlockAction::Forbidden) },
+    ]
+}
+
+/// 最も長く一致したネットワークで許可/拒否を決める (同じネットワークなら拒否)
+/// どれにも一致しないIPは、許可リストが空なら許可、そうでなければ拒否する
+#[derive(Clone, Default)]
+pub struct IpAccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    // 追加のリストファイル (1行に "allow 203.0.113.0/24" か "deny 198.51.100.7"。更新すると再読み込みする)
+    // 読めない、空、解釈できない行がある場合は、起動時はすべて拒否し、再読み込み時は前のリストを使う
+    // 相対パスはdata_pathから。chrootする場合はdata_pathの中に置く
+    pub file: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct IpAccessPath {
+    // リクエストパスのプレフィックス ("/admin/" など。サブディレクトリも対象)
+    pub path: String,
+    pub list: IpAccessList,
+}
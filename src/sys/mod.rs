@@ -1,2 +1,6 @@
 pub mod init;
-pub mod app_set;
\ No newline at end of file
+pub mod app_set;
+pub mod site;
+pub mod privilege;
+pub mod proxy_protocol;
+pub mod export;
\ No newline at end of file
@@ -0,0 +1,80 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+use super::init::AppConfig;
+
+// chrootしたディレクトリの絶対パス
+static CHROOT_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// ソケットのバインドとファイルの読み込みが終わった後に呼び出し、
+/// 設定されたユーザー/グループへ権限を落とす。失敗した場合はErrを返すので起動を中止すること。
+pub fn drop_privileges(app_config: &AppConfig) -> io::Result<()> {
+    if app_config.run_user.is_none() && app_config.run_group.is_none() && !app_config.chroot {
+        return Ok(());
+    }
+
+    // chroot後は/etc/passwdなどが読めないので先に解決しておく
+    let user = match &app_config.run_user {
+        Some(name) => Some(lookup_user(name)?),
+        None => None,
+    };
+    let gid = match &app_config.run_group {
+        Some(name) => Some(lookup_group(name)?),
+        None => user.as_ref().map(|u| u.gid),
+    };
+
+    if app_config.chroot {
+        let dir = std::path::absolute(&app_config.data_path)?;
+        unistd::chroot(&dir).map_err(io::Error::from)?;
+        unistd::chdir("/").map_err(io::Error::from)?;
+        let _ = CHROOT_DIR.set(dir);
+    }
+
+    // グループ -> ユーザーの順に落とさないとsetgidできなくなる
+    if let Some(gid) = gid {
+        unistd::setgroups(&[gid]).map_err(io::Error::from)?;
+        unistd::setgid(gid).map_err(io::Error::from)?;
+    }
+    if let Some(user) = &user {
+        unistd::setuid(user.uid).map_err(io::Error::from)?;
+    }
+
+    // rootに戻れないことを確認する
+    if user.is_some() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err(io::Error::other("privilege drop failed: able to regain root"));
+    }
+
+    println!(
+        "Privileges dropped: uid={} gid={} chroot={}",
+        unistd::getuid(),
+        unistd::getgid(),
+        app_config.chroot
+    );
+    Ok(())
+}
+
+/// AppConfig::resolve_data_filesで解決したパスを今のルートからのパスにする
+/// chroot後はdata_pathの中のパスをルートからのパスにする (外のパスは開けないのでそのまま)
+pub fn current_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match CHROOT_DIR.get().and_then(|dir| path.strip_prefix(dir).ok()) {
+        Some(relative) => Path::new("/").join(relative),
+        None => path.to_path_buf(),
+    }
+}
+
+fn lookup_user(name: &str) -> io::Result<User> {
+    User::from_name(name)
+        .map_err(io::Error::from)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("unknown user: {}", name)))
+}
+
+fn lookup_group(name: &str) -> io::Result<Gid> {
+    Group::from_name(name)
+        .map_err(io::Error::from)?
+        .map(|g| g.gid)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("unknown group: {}", name)))
+}
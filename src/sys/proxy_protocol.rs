@@ -0,0 +1,355 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_http::{body::MessageBody, error::DispatchError, HttpService, Protocol, Request, Response};
+use actix_server::ServerBuilder;
+use actix_service::{fn_service, map_config, IntoServiceFactory, Service, ServiceFactory, ServiceFactoryExt};
+use actix_web::dev::{AppConfig as ActixAppConfig, Extensions};
+use actix_web::rt::net::TcpStream;
+use bytes::{Buf, Bytes, BytesMut};
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use super::init::AppConfig;
+use crate::handler::block_rules::ConnectionFd;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+const V2_MAX_LEN: usize = 16 + 4096;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// HTTP/2の接続プリフェイスの先頭 (HttpServerのh2c判定と同じ)
+const H2_PREFACE: &[u8; 12] = b"PRI * HTTP/2";
+
+/// PROXYプロトコルヘッダーを読み取った後のストリーム
+/// ヘッダーの後ろまで読み込んでしまったバイト列を先に返す
+pub struct ProxiedStream {
+    prefix: Bytes,
+    inner: TcpStream,
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..len]);
+            self.prefix.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl ProxiedStream {
+    /// 平文のHTTP/2 (prior knowledge) かHTTP/1か
+    async fn protocol(&self) -> io::Result<Protocol> {
+        let mut head = [0u8; 12];
+        let len = if self.prefix.is_empty() {
+            self.inner.peek(&mut head).await?
+        } else {
+            let len = self.prefix.len().min(head.len());
+            head[..len].copy_from_slice(&self.prefix[..len]);
+            len
+        };
+        Ok(if &head[..len] == H2_PREFACE { Protocol::Http2 } else { Protocol::Http1 })
+    }
+}
+
+impl AsRawFd for ProxiedStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Parsed {
+    // まだヘッダーが揃っていない
+    Incomplete,
+    // PROXYヘッダーではない
+    None,
+    // (ヘッダー長, 元のクライアントアドレス)
+    Header(usize, Option<SocketAddr>),
+}
+
+/// 接続直後にPROXYヘッダーを読み取り、元のクライアントアドレスを返す
+/// 信頼できないピアの場合はヘッダーを解釈せずそのまま通す
+pub async fn accept(mut io: TcpStream, trusted_proxies: &[IpNet]) -> io::Result<(ProxiedStream, Option<SocketAddr>)> {
+    let peer = io.peer_addr().ok();
+    let trusted = peer.map(|p| trusted_proxies.iter().any(|net| net.contains(&p.ip()))).unwrap_or(false);
+    if !trusted {
+        return Ok((ProxiedStream { prefix: Bytes::new(), inner: io }, peer));
+    }
+
+    let mut buf = BytesMut::with_capacity(256);
+    let parsed = tokio::time::timeout(HEADER_TIMEOUT, async {
+        loop {
+            match parse(&buf)? {
+                Parsed::Incomplete => {
+                    if io.read_buf(&mut buf).await? == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in PROXY header"));
+                    }
+                }
+                parsed => return Ok(parsed),
+            }
+        }
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY header timeout"))??;
+
+    let addr = match parsed {
+        Parsed::Header(len, addr) => {
+            buf.advance(len);
+            addr.or(peer)
+        }
+        _ => peer,
+    };
+    Ok((ProxiedStream { prefix: buf.freeze(), inner: io }, addr))
+}
+
+fn parse(buf: &[u8]) -> io::Result<Parsed> {
+    if buf.is_empty() {
+        return Ok(Parsed::Incomplete);
+    }
+    if b"PROXY ".starts_with(&buf[..buf.len().min(6)]) {
+        return parse_v1(buf);
+    }
+    if V2_SIGNATURE.starts_with(&buf[..buf.len().min(12)]) {
+        return parse_v2(buf);
+    }
+    Ok(Parsed::None)
+}
+
+fn parse_v1(buf: &[u8]) -> io::Result<Parsed> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(Parsed::Incomplete),
+        None => return Err(invalid("PROXY v1 header too long")),
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("PROXY v1 header is not utf-8"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let addr = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid("malformed PROXY v1 header")),
+    };
+    Ok(Parsed::Header(end + 2, addr))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Parsed> {
+    if buf.len() < 16 {
+        return Ok(Parsed::Incomplete);
+    }
+    let ver_cmd = buf[12];
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if 16 + len > V2_MAX_LEN {
+        return Err(invalid("PROXY v2 header too long"));
+    }
+    if buf.len() < 16 + len {
+        return Ok(Parsed::Incomplete);
+    }
+    let body = &buf[16..16 + len];
+    // LOCALコマンドはプロキシ自身の接続(ヘルスチェックなど)
+    if ver_cmd & 0x0f == 0 {
+        return Ok(Parsed::Header(16 + len, None));
+    }
+    let addr = match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]])))
+        }
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([body[32], body[33]])))
+        }
+        _ => None,
+    };
+    Ok(Parsed::Header(16 + len, addr))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// PROXYプロトコル対応のHTTPサーバーをバインドする
+/// HttpServer::bindと同じ形のファクトリを受け取る (HTTP/1と平文のHTTP/2。TLSはプロキシで終端する)
+pub fn bind<F, I, S, B>(app_config: &AppConfig, factory: F) -> io::Result<ServerBuilder>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = ActixAppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let trusted_proxies = app_config.trusted_proxies.clone();
+    // HttpServerと同じくバインドするアドレスをアプリのホストとアドレスにする (TLSは扱わないのでsecureはfalse)
+    let addr = app_config.server_bind.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot resolve {}", app_config.server_bind)))?;
+
+    ServerBuilder::default()
+        .workers(app_config.server_workers)
+        .backlog(app_config.server_backlog)
+        .bind("proxy-protocol", app_config.server_bind.clone(), move || {
+            let trusted_proxies = trusted_proxies.clone();
+            let app = factory()
+                .into_factory()
+                .map_err(|err| err.into().error_response());
+
+            fn_service(move |io: TcpStream| {
+                let trusted_proxies = trusted_proxies.clone();
+                async move {
+                    let (io, addr) = accept(io, &trusted_proxies).await.map_err(DispatchError::Io)?;
+                    let protocol = io.protocol().await.map_err(DispatchError::Io)?;
+                    Ok::<_, DispatchError>((io, protocol, addr))
+                }
+            })
+            .and_then(
+                HttpService::build()
+                    .on_connect_ext(|io: &ProxiedStream, data: &mut Extensions| {
+                        data.insert(ConnectionFd(io.as_raw_fd()));
+                    })
+                    // AppConfig::newは公開されていないのでactix-testと同じ関数で作る
+                    .finish(map_config(app, move |_| ActixAppConfig::__priv_test_new(false, addr.to_string(), addr))),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend([ver_cmd, family]);
+        buf.extend((body.len() as u16).to_be_bytes());
+        buf.extend(body);
+        buf
+    }
+
+    #[test]
+    fn v1_tcp4_and_tcp6() {
+        let buf = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nGET / HTTP/1.1\r\n";
+        let len = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\n".len();
+        assert_eq!(parse(buf).unwrap(), Parsed::Header(len, Some("203.0.113.7:51234".parse().unwrap())));
+
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 8080 443\r\n";
+        assert_eq!(parse(buf).unwrap(), Parsed::Header(buf.len(), Some("[2001:db8::1]:8080".parse().unwrap())));
+    }
+
+    #[test]
+    fn v1_unknown_has_no_address() {
+        let buf = b"PROXY UNKNOWN\r\n";
+        assert_eq!(parse(buf).unwrap(), Parsed::Header(buf.len(), None));
+    }
+
+    #[test]
+    fn v1_incomplete_until_crlf() {
+        assert_eq!(parse(b"").unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(b"PRO").unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443").unwrap(), Parsed::Incomplete);
+    }
+
+    #[test]
+    fn v1_rejects_malformed() {
+        assert!(parse(b"PROXY TCP4 not-an-ip 192.0.2.1 51234 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 203.0.113.7 192.0.2.1 99999 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 203.0.113.7 192.0.2.1 51234\r\n").is_err());
+        assert!(parse(b"PROXY  TCP4 203.0.113.7 192.0.2.1 51234 443\r\n").is_err());
+        assert!(parse(&[b'P', b'R', b'O', b'X', b'Y', b' ', 0xff, b'\r', b'\n']).is_err());
+    }
+
+    #[test]
+    fn v1_rejects_overlong_header() {
+        let mut buf = b"PROXY TCP4 ".to_vec();
+        buf.resize(V1_MAX_LEN, b'1');
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn not_a_proxy_header() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n").unwrap(), Parsed::None);
+        assert_eq!(parse(b"PRI * HTTP/2.0\r\n").unwrap(), Parsed::None);
+        assert_eq!(parse(b"\r\n\r\nX").unwrap(), Parsed::None);
+    }
+
+    #[test]
+    fn v2_tcp4_and_tcp6() {
+        let mut body = vec![203, 0, 113, 7, 192, 0, 2, 1];
+        body.extend(51234u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        let buf = v2(0x21, 0x11, &body);
+        assert_eq!(parse(&buf).unwrap(), Parsed::Header(buf.len(), Some("203.0.113.7:51234".parse().unwrap())));
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut body = src.octets().to_vec();
+        body.extend([0; 16]);
+        body.extend(8080u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        let buf = v2(0x21, 0x21, &body);
+        assert_eq!(parse(&buf).unwrap(), Parsed::Header(buf.len(), Some("[2001:db8::1]:8080".parse().unwrap())));
+    }
+
+    #[test]
+    fn v2_skips_tlvs() {
+        let mut body = vec![203, 0, 113, 7, 192, 0, 2, 1, 0, 80, 1, 187];
+        body.extend([0x04, 0x00, 0x01, 0x00]);
+        let mut buf = v2(0x21, 0x11, &body);
+        let len = buf.len();
+        buf.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(parse(&buf).unwrap(), Parsed::Header(len, Some("203.0.113.7:80".parse().unwrap())));
+    }
+
+    #[test]
+    fn v2_local_and_unknown_family_have_no_address() {
+        let buf = v2(0x20, 0x00, &[]);
+        assert_eq!(parse(&buf).unwrap(), Parsed::Header(16, None));
+        let buf = v2(0x21, 0x31, &[0; 216]);
+        assert_eq!(parse(&buf).unwrap(), Parsed::Header(buf.len(), None));
+        // アドレス部分が短すぎる
+        let buf = v2(0x21, 0x11, &[203, 0, 113, 7]);
+        assert_eq!(parse(&buf).unwrap(), Parsed::Header(buf.len(), None));
+    }
+
+    #[test]
+    fn v2_incomplete_and_invalid() {
+        let buf = v2(0x21, 0x11, &[0; 12]);
+        assert_eq!(parse(&buf[..10]).unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(&buf[..20]).unwrap(), Parsed::Incomplete);
+        assert!(parse(&v2(0x11, 0x11, &[0; 12])).is_err());
+
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend([0x21, 0x11]);
+        buf.extend(u16::MAX.to_be_bytes());
+        assert!(parse(&buf).is_err());
+    }
+}
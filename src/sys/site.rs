@@ -0,0 +1,251 @@
+use std::{collections::HashMap, fs, path::Path, sync::Arc, time::SystemTime};
+
+use super::init::{AppConfig, SiteConfig};
+use super::privilege::current_path;
+use crate::handler::{
+    basic_auth::BasicAuth, err_page::ErrHandler, front_matter::{page_headers, split_front_matter}, markdown::render_markdown, path::normalize_key,
+    render_cache::etag_for, rewrite::RewriteRules, router::Router, suggest::PathSuggester, template_helpers,
+};
+use bytes::Bytes;
+use serde_json::Value;
+use tera::Tera;
+
+// テンプレートに渡すデータファイルを置くディレクトリ (配信はしない)
+const DATA_DIR: &str = "_data/";
+
+/// バーチャルホスト1つ分のデータ
+pub struct Site {
+    pub site_config: SiteConfig,
+    pub err_handler: ErrHandler,
+    pub handler: Router,
+}
+
+/// data_pathから読み込んだファイルと、それから作ったテンプレートなど
+#[derive(Clone)]
+pub struct SiteContent {
+    pub template: Tera,
+    pub static_cache: HashMap<String, Bytes>,
+    pub modified: HashMap<String, SystemTime>,
+    // .mdなど事前に描画したHTML (static_cacheと同じキー)
+    pub rendered: HashMap<String, Bytes>,
+    // HTMLテンプレートのフロントマター (static_cacheと同じキー)
+    pub page_meta: HashMap<String, Value>,
+    // _data/ 以下のファイルをまとめたもの。テンプレートには data として渡す
+    pub data: Value,
+    // 静的ファイルと事前に描画したHTMLのETag
+    pub etags: HashMap<String, String>,
+}
+
+impl Site {
+    pub async fn new(
+        app_config: &AppConfig,
+        site_config: SiteConfig,
+        rewrite_rules: Arc<RewriteRules>,
+        basic_auth: Arc<BasicAuth>,
+    ) -> Self {
+        let (mut static_cache, modified) = Site::load_cache_static_files(Path::new(&site_config.data_path), app_config.unicode_nfc);
+        for key in Site::private_keys(app_config, Path::new(&site_config.data_path)) {
+            let key = if app_config.unicode_nfc { normalize_key(&key) } else { key };
+            let before = static_cache.len();
+            static_cache.retain(|k, _| *k != key && !k.starts_with(&format!("{}/", key)));
+            if static_cache.len() != before {
+                eprintln!("Private file is inside data_path and will not be served: {}", key);
+            }
+        }
+        let data = Site::load_data_files(&static_cache);
+        static_cache.retain(|key, _| !key.starts_with(DATA_DIR));
+        let (mut template, page_meta) = Site::load_template_html(&static_cache, &data, app_config);
+        Site::load_template_dir(&mut template, Path::new(&site_config.template_path));
+        let rendered = Site::render_markdown_files(&static_cache, &template, &app_config.markdown_layout, &data);
+        println!("Site loaded: {} ({} files)", site_config.name, static_cache.len());
+
+        let mut err_handler = ErrHandler::new(template.clone()).await;
+        err_handler.status_color.extend(site_config.err_colors.clone());
+        err_handler.path_suggester = PathSuggester::new(
+            // 認証が必要なパスは候補に出さない
+            static_cache.keys().filter(|key| !basic_auth.is_protected(key)),
+            app_config.suggest_max_results,
+            app_config.suggest_threshold,
+        );
+
+        let mut etags: HashMap<String, String> = static_cache.iter()
+            .filter(|(key, _)| !key.ends_with(".html"))
+            .map(|(key, content)| (key.clone(), etag_for(content)))
+            .collect();
+        etags.extend(rendered.iter().map(|(key, html)| (key.clone(), etag_for(html))));
+
+        let content = SiteContent {
+            template,
+            static_cache,
+            modified,
+            rendered,
+            page_meta,
+            data,
+            etags,
+        };
+
+        Site {
+            err_handler,
+            handler: Router::new(app_config, &site_config, content, rewrite_rules, basic_auth),
+            site_config,
+        }
+    }
+
+    /// Hostがこのサイトのパターンに一致するか
+    pub fn matches_host(&self, host: &str) -> bool {
+        self.site_config.hosts.iter().any(|pattern| {
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(suffix) => host.len() > suffix.len() + 1
+                    && host.ends_with(suffix)
+                    && host[..host.len() - suffix.len()].ends_with('.'),
+                None => host == pattern,
+            }
+        })
+    }
+
+    /// data_pathの中にあるhtpasswdやセッションなどのキー (ディレクトリならその下すべてを配信しない)
+    fn private_keys(app_config: &AppConfig, data_path: &Path) -> Vec<String> {
+        let Ok(data_path) = data_path.canonicalize() else {
+            return Vec::new();
+        };
+        app_config.private_files().into_iter()
+            .filter_map(|file| {
+                let path = current_path(file).canonicalize().ok()?;
+                let relative = path.strip_prefix(&data_path).ok()?;
+                let segments: Option<Vec<&str>> = relative.iter().map(|s| s.to_str()).collect();
+                Some(segments?.join("/")).filter(|key| !key.is_empty())
+            })
+            .collect()
+    }
+
+    /// キーはdata_pathからの相対パスを "/" で繋いだもの (nfcがtrueならNFC正規化)
+    /// path::normalize_pathの結果と一致させること
+    /// 更新日時も同じキーで返す
+    fn load_cache_static_files(dir: &Path, nfc: bool) -> (HashMap<String, Bytes>, HashMap<String, SystemTime>) {
+        let mut cache = HashMap::new();
+        let mut modified = HashMap::new();
+        Site::load_cache_dir(dir, "", nfc, &mut cache, &mut modified);
+        (cache, modified)
+    }
+
+    fn load_cache_dir(
+        dir: &Path,
+        prefix: &str,
+        nfc: bool,
+        cache: &mut HashMap<String, Bytes>,
+        modified: &mut HashMap<String, SystemTime>,
+    ) {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
+                    let key = format!("{}{}", prefix, filename);
+                    let key = if nfc { normalize_key(&key) } else { key };
+                    if path.is_dir() {
+                        Site::load_cache_dir(&path, &format!("{}/", key), nfc, cache, modified);
+                    } else if path.is_file() {
+                        if let Ok(content) = fs::read(&path) {
+                            if let Ok(time) = entry.metadata().and_then(|m| m.modified()) {
+                                modified.insert(key.clone(), time);
+                            }
+                            cache.insert(key, Bytes::from(content));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// フロントマターは取り除いてテンプレートにし、キーごとに返す
+    /// テンプレート用の関数とフィルターもここで登録する
+    fn load_template_html(static_cach: &HashMap<String, Bytes>, data: &Value, app_config: &AppConfig) -> (Tera, HashMap<String, Value>) {
+        let mut tera = Tera::default();
+        template_helpers::register(&mut tera, static_cach, data, app_config);
+        let mut page_meta = HashMap::new();
+        for (filename, content) in static_cach {
+            if filename.ends_with(".html") {
+                if let Ok(template_content) = std::str::from_utf8(content) {
+                    let (front_matter, body) = split_front_matter(template_content).unwrap_or_else(|err| {
+                        eprintln!("Front matter error: {}: {}", filename, err);
+                        (Value::Null, template_content)
+                    });
+                    tera.add_raw_template(filename, body).expect("Failed to add template");
+                    for err in page_headers(&front_matter).1 {
+                        eprintln!("Front matter error: {}: {} (ignored)", filename, err);
+                    }
+                    page_meta.insert(filename.clone(), front_matter);
+                }
+            }
+        }
+        (tera, page_meta)
+    }
+
+    /// _data/nav.json -> data.nav、_data/shop/prices.toml -> data.shop.prices のようにまとめる
+    fn load_data_files(static_cache: &HashMap<String, Bytes>) -> Value {
+        let mut data = serde_json::Map::new();
+        for (key, content) in static_cache {
+            let Some(rest) = key.strip_prefix(DATA_DIR) else { continue };
+            let Some((stem, ext)) = rest.rsplit_once('.') else { continue };
+            let Ok(text) = std::str::from_utf8(content) else { continue };
+            let value: Result<Value, String> = match ext {
+                "json" => serde_json::from_str(text).map_err(|e| e.to_string()),
+                "toml" => toml::from_str(text).map_err(|e| e.to_string()),
+                "yaml" | "yml" => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+                _ => continue,
+            };
+            let value = match value {
+                Ok(value) => value,
+                Err(err) => {
+                    eprintln!("Data file error: {}: {}", key, err);
+                    continue;
+                }
+            };
+
+            let mut segments: Vec<&str> = stem.split('/').collect();
+            let last = segments.pop().unwrap_or(stem);
+            let mut object = &mut data;
+            for segment in segments {
+                let entry = object.entry(segment.to_string())
+                    .or_insert_with(|| Value::Object(serde_json::Map::new()));
+                if !entry.is_object() {
+                    *entry = Value::Object(serde_json::Map::new());
+                }
+                object = entry.as_object_mut().unwrap();
+            }
+            object.insert(last.to_string(), value);
+        }
+        Value::Object(data)
+    }
+
+    fn render_markdown_files(static_cache: &HashMap<String, Bytes>, template: &Tera, layout: &str, data: &Value) -> HashMap<String, Bytes> {
+        let mut rendered = HashMap::new();
+        for (key, content) in static_cache {
+            if !key.ends_with(".md") {
+                continue;
+            }
+            let Ok(source) = std::str::from_utf8(content) else { continue };
+            match render_markdown(source, template, layout, data) {
+                Ok(html) => {
+                    rendered.insert(key.clone(), Bytes::from(html));
+                }
+                Err(err) => eprintln!("Markdown rendering error: {}: {}", key, err),
+            }
+        }
+        rendered
+    }
+
+    /// err_template.htmlなど配信はしないテンプレートを読み込む
+    fn load_template_dir(tera: &mut Tera, dir: &Path) {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_file() && path.extension().is_some_and(|e| e == "html") {
+                    if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
+                        tera.add_template_file(&path, Some(filename)).expect("Failed to add template");
+                    }
+                }
+            }
+        }
+    }
+}